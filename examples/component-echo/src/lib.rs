@@ -0,0 +1,35 @@
+//! A service built as a WebAssembly component with `#[stateroom_wasm(component)]`.
+//! `stateroom-wasm-host` also uses it as a test fixture for the component macro.
+
+use stateroom_wasm::*;
+
+#[stateroom_wasm(component)]
+#[derive(Default)]
+struct ComponentEcho;
+
+impl StateroomService for ComponentEcho {
+    fn init(&mut self, ctx: &impl StateroomContext) {
+        ctx.set_timer(500);
+    }
+
+    fn connect(&mut self, client_id: ClientId, ctx: &impl StateroomContext) {
+        if let Some(protocol) = ctx.client_protocol(client_id) {
+            ctx.send_message(client_id, protocol);
+        }
+    }
+
+    fn message(&mut self, client_id: ClientId, message: MessagePayload, ctx: &impl StateroomContext) {
+        ctx.send_message(client_id, message);
+    }
+
+    fn timer(&mut self, ctx: &impl StateroomContext) {
+        ctx.send_message(
+            MessageRecipient::Broadcast,
+            format!("{} clients", ctx.connected_clients().len()),
+        );
+    }
+
+    fn disconnect(&mut self, _: ClientId, ctx: &impl StateroomContext) {
+        ctx.close_room("done");
+    }
+}
@@ -16,8 +16,8 @@ fn main() -> anyhow::Result<()> {
     let opts = Opts::parse();
 
     match opts.subcommand {
-        SubCommand::Serve(serve_opts) => serve(serve_opts),
+        SubCommand::Serve(serve_opts) => serve(*serve_opts),
         SubCommand::Dev { port } => dev(port),
-        SubCommand::Build => build(),
+        SubCommand::Build { precompile } => build(precompile),
     }
 }
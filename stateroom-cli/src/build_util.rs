@@ -3,21 +3,27 @@ use anyhow::{anyhow, Result};
 use cargo_metadata::Message;
 use std::{
     fs::read_to_string,
-    path::PathBuf,
+    path::{Path, PathBuf},
     process::{Command, Stdio},
 };
 use wasm_bindgen_cli_support::Bindgen;
 
 pub fn locate_config() -> anyhow::Result<StateroomConfig> {
-    if let Ok(r) = read_to_string("stateroom.toml") {
-        tracing::info!("Loading config from file (stateroom.toml)");
-        toml::from_str(&r).map_err(|e| e.into())
+    if Path::new("stateroom.toml").exists() {
+        load_config("stateroom.toml")
     } else {
         tracing::info!("Didn't find a stateroom.toml file in current directory, using default");
         Ok(StateroomConfig::default())
     }
 }
 
+pub fn load_config(path: impl AsRef<Path>) -> anyhow::Result<StateroomConfig> {
+    let path = path.as_ref();
+    tracing::info!(?path, "Loading config from file");
+    let config = read_to_string(path)?;
+    toml::from_str(&config).map_err(|e| e.into())
+}
+
 pub fn run_cargo_build_command(
     package: &Option<String>,
     target: &str,
@@ -92,7 +98,8 @@ pub struct BuildResult {
 
 pub fn do_build(config: &StateroomConfig) -> Result<BuildResult> {
     tracing::info!("Building service");
-    let server_wasm = run_cargo_build_command(&config.service.package, "wasm32-wasi", true)?;
+    let server_wasm =
+        run_cargo_build_command(&config.service.package, config.service.target(), true)?;
 
     let client_wasm = if let Some(client_config) = &config.client {
         tracing::info!("Building client");
@@ -1,4 +1,6 @@
 use clap::Parser;
+use stateroom_process_host::Encoding;
+use stateroom_server::RateLimitAction;
 
 #[derive(Parser)]
 pub struct Opts {
@@ -9,9 +11,14 @@ pub struct Opts {
 #[derive(Parser)]
 pub enum SubCommand {
     /// Run a dev server to host a given Stateroom module.
-    Serve(ServeCommand),
+    Serve(Box<ServeCommand>),
 
-    Build,
+    Build {
+        /// Also compile server.wasm ahead of time into server.cwasm, which
+        /// `stateroom serve` loads without compiling.
+        #[clap(long)]
+        precompile: bool,
+    },
     Dev {
         #[clap(default_value = "8080")]
         port: u16,
@@ -29,9 +36,16 @@ pub struct LoginCommand {
 
 #[derive(Parser)]
 pub struct ServeCommand {
-    /// The module (.wasm file) to serve.
+    /// The module (.wasm, .wat, or precompiled .cwasm file) to serve, a
+    /// directory produced by `stateroom build`, or with --process, an
+    /// executable to run as a child process for each room.
     pub module: String,
 
+    /// Run MODULE as a child process for each room, instead of loading it as
+    /// WebAssembly.
+    #[clap(long)]
+    pub process: bool,
+
     /// The port to serve on.
     #[clap(short, long, default_value = "8080")]
     pub port: u16,
@@ -44,4 +58,93 @@ pub struct ServeCommand {
     /// assumed to be disconnected.
     #[clap(short = 't', long, default_value = "120")]
     pub heartbeat_timeout: u64,
+
+    /// An origin that browsers may connect from (may be repeated). Wildcard
+    /// subdomains like `https://*.example.com` are supported. Replaces the
+    /// `allowed_origins` of the --config file. If neither is given,
+    /// connections from any origin are accepted.
+    #[clap(long = "allowed-origin")]
+    pub allowed_origins: Vec<String>,
+
+    /// The largest message (in bytes) that a client may send.
+    #[clap(long)]
+    pub max_message_size: Option<usize>,
+
+    /// The number of messages per second that each client may send.
+    #[clap(long)]
+    pub client_messages_per_second: Option<u32>,
+
+    /// The number of bytes per second that each client may send.
+    #[clap(long)]
+    pub client_bytes_per_second: Option<u32>,
+
+    /// The number of messages per second that all clients of a room may
+    /// send combined.
+    #[clap(long)]
+    pub room_messages_per_second: Option<u32>,
+
+    /// The number of bytes per second that all clients of a room may send
+    /// combined.
+    #[clap(long)]
+    pub room_bytes_per_second: Option<u32>,
+
+    /// The number of bytes that the byte limits allow in a single burst.
+    /// Defaults to --max-message-size.
+    #[clap(long)]
+    pub burst_bytes: Option<u32>,
+
+    /// What to do with messages over a rate limit: drop, notify, or
+    /// disconnect.
+    #[clap(long, default_value = "drop")]
+    pub rate_limit_action: RateLimitAction,
+
+    /// A WebSocket subprotocol that the service supports (may be repeated),
+    /// in order of preference.
+    #[clap(long = "subprotocol")]
+    pub subprotocols: Vec<String>,
+
+    /// Replay up to this many of a room's most recent broadcasts to each
+    /// client that joins it.
+    #[clap(long)]
+    pub history_messages: Option<usize>,
+
+    /// Only replay broadcasts sent within this many seconds.
+    #[clap(long, requires = "history_messages")]
+    pub history_seconds: Option<u64>,
+
+    /// Close a room once it has had no clients for this many seconds,
+    /// discarding its service's state. By default, rooms are kept until their
+    /// service closes them.
+    #[clap(long)]
+    pub empty_room_timeout: Option<u64>,
+
+    /// The encoding of frames exchanged with a service process: json or
+    /// bincode. Only used with --process.
+    #[clap(long, default_value = "json")]
+    pub process_encoding: Encoding,
+
+    /// A directory in which to cache compiled modules, so that a module is only
+    /// compiled the first time it is served. Cached modules are run without
+    /// validation, so no other user should be able to write to it.
+    #[clap(long)]
+    pub cache_dir: Option<String>,
+
+    /// Preallocate memory for this many concurrent rooms with wasmtime's
+    /// pooling allocator, which makes creating rooms cheaper. Rooms beyond the
+    /// limit can't be created until others close.
+    #[clap(long)]
+    pub pooled_rooms: Option<u32>,
+
+    /// Expose unauthenticated admin endpoints, such as
+    /// `/admin/rooms/{room_id}/output` for a room's recent output. Only use
+    /// this where untrusted clients can't reach the server.
+    #[clap(long)]
+    pub admin_api: bool,
+
+    /// A stateroom.toml file whose `[service.wasi]` section sets the
+    /// environment variables, directories, and other WASI capabilities given
+    /// to a WebAssembly service, and whose `allowed_origins` are used unless
+    /// --allowed-origin is given. Without it, the service gets no capabilities.
+    #[clap(long)]
+    pub config: Option<String>,
 }
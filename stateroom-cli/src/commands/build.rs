@@ -1,6 +1,7 @@
 use crate::build_util::{do_build, locate_config};
 use anyhow::Context;
 use fs_extra::dir::CopyOptions;
+use stateroom_wasm_host::WasmHostFactory;
 use std::{
     fs::{copy, create_dir, remove_dir_all},
     path::Path,
@@ -9,7 +10,7 @@ use std::{
 const OUTPUT_DIR: &str = "dist";
 const STATIC_DIR: &str = "static";
 
-pub fn build() -> anyhow::Result<()> {
+pub fn build(precompile: bool) -> anyhow::Result<()> {
     let config = locate_config()?; // TODO: default to a configuration if file not found.
 
     let build_result = do_build(&config)?;
@@ -25,6 +26,14 @@ pub fn build() -> anyhow::Result<()> {
     )
     .context("Couldn't copy server.wasm.")?;
 
+    if precompile {
+        WasmHostFactory::precompile(
+            Path::new(OUTPUT_DIR).join("server.wasm"),
+            Path::new(OUTPUT_DIR).join("server.cwasm"),
+        )
+        .context("Couldn't precompile server.wasm.")?;
+    }
+
     create_dir(Path::new(OUTPUT_DIR).join(STATIC_DIR))
         .context("Couldn't create empty static directory.")?;
 
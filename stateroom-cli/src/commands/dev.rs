@@ -1,17 +1,23 @@
+use super::serve::serve_module;
 use crate::build_util::{do_build, locate_config};
-use stateroom_server::Server;
-use stateroom_wasm_host::WasmHostFactory;
+use stateroom_server::{AllowedOrigins, Server};
+use std::path::Path;
 
 pub fn dev(port: u16) -> anyhow::Result<()> {
     let config = locate_config()?; // TODO: default to a configuration if file not found.
 
     let build_result = do_build(&config)?;
-    let host_factory = WasmHostFactory::new(build_result.server_wasm)?;
-
-    Server::default()
+    let server = Server::default()
         .with_port(port)
         .with_static_path(config.static_files)
         .with_client_path(build_result.client_wasm)
-        .serve(host_factory)
-        .map_err(|e| e.into())
+        .with_allowed_origins(config.allowed_origins.map(AllowedOrigins::new));
+
+    serve_module(
+        server,
+        Path::new(&build_result.server_wasm),
+        None,
+        None,
+        config.service.wasi.capabilities(),
+    )
 }
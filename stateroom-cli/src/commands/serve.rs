@@ -1,16 +1,49 @@
-use crate::cli_opts::ServeCommand;
-use stateroom_server::Server;
-use stateroom_wasm_host::WasmHostFactory;
-use std::{ffi::OsStr, path::Path, time::Duration};
+use crate::{build_util::load_config, cli_opts::ServeCommand};
+use stateroom::HistoryLimit;
+use stateroom_process_host::ProcessHostFactory;
+use stateroom_server::{AllowedOrigins, RateLimits, Server};
+use stateroom_wasm_host::{
+    is_component, Capabilities, ComponentHostFactory, ModuleLoader, PoolingConfig, WasmHostFactory,
+    PRECOMPILED_EXTENSION,
+};
+use std::{ffi::OsStr, fs, path::Path, time::Duration};
 
 pub fn serve(serve_opts: ServeCommand) -> anyhow::Result<()> {
     let ServeCommand {
         module,
+        process,
         port,
         heartbeat_interval,
         heartbeat_timeout,
+        allowed_origins,
+        max_message_size,
+        client_messages_per_second,
+        client_bytes_per_second,
+        room_messages_per_second,
+        room_bytes_per_second,
+        burst_bytes,
+        rate_limit_action,
+        subprotocols,
+        history_messages,
+        history_seconds,
+        empty_room_timeout,
+        process_encoding,
+        cache_dir,
+        pooled_rooms,
+        admin_api,
+        config,
     } = serve_opts;
 
+    let config = config.map(load_config).transpose()?;
+    let capabilities = match &config {
+        Some(config) => config.service.wasi.capabilities(),
+        None => Capabilities::default(),
+    };
+    let allowed_origins = merge_allowed_origins(
+        allowed_origins,
+        config.and_then(|config| config.allowed_origins),
+    );
+
     let path = Path::new(&module);
     let ext = path
         .extension()
@@ -21,16 +54,47 @@ pub fn serve(serve_opts: ServeCommand) -> anyhow::Result<()> {
         heartbeat_interval: Duration::from_secs(heartbeat_interval),
         heartbeat_timeout: Duration::from_secs(heartbeat_timeout),
         port,
+        allowed_origins: allowed_origins.map(AllowedOrigins::new),
+        rate_limits: RateLimits {
+            max_message_size,
+            client_messages_per_second,
+            client_bytes_per_second,
+            room_messages_per_second,
+            room_bytes_per_second,
+            burst_bytes,
+            action: rate_limit_action,
+        },
+        subprotocols,
+        history: history_messages.map(|max_messages| HistoryLimit {
+            max_messages,
+            max_age: history_seconds.map(Duration::from_secs),
+        }),
+        empty_room_timeout: empty_room_timeout.map(Duration::from_secs),
+        admin_api,
         ..Server::default()
     };
 
-    if let Some("wasm" | "wat") = ext.as_deref() {
-        let host_factory = WasmHostFactory::new(&module)?;
-        server_settings.serve(host_factory).map_err(|e| e.into())
-    } else if path.is_file() {
-        unimplemented!("Only .wasm and .wat files are supported.");
+    if process {
+        if !path.is_file() {
+            return Err(anyhow::anyhow!("Expected an executable file."));
+        }
+        let process_factory = ProcessHostFactory::new(path).with_encoding(process_encoding);
+        server_settings.serve(process_factory).map_err(|e| e.into())
+    } else if let Some("wasm" | "wat" | "cwasm") = ext.as_deref() {
+        serve_module(
+            server_settings,
+            path,
+            cache_dir.as_deref(),
+            pooled_rooms,
+            capabilities,
+        )
     } else if path.is_dir() {
-        let server_module = path.join("server.wasm");
+        let precompiled_module = path.join("server.cwasm");
+        let server_module = if precompiled_module.exists() {
+            precompiled_module
+        } else {
+            path.join("server.wasm")
+        };
 
         if !server_module.exists() {
             return Err(anyhow::anyhow!("Expected server.wasm"));
@@ -44,13 +108,100 @@ pub fn serve(serve_opts: ServeCommand) -> anyhow::Result<()> {
             None
         };
 
-        let host_factory = WasmHostFactory::new(&server_module)?;
-
-        server_settings
-            .with_static_path(static_dir)
-            .serve(host_factory)
-            .map_err(|e| e.into())
+        serve_module(
+            server_settings.with_static_path(static_dir),
+            &server_module,
+            cache_dir.as_deref(),
+            pooled_rooms,
+            capabilities,
+        )
+    } else if path.is_file() {
+        // Refuse to run a file that was meant to be a module, such as a typo'd path.
+        Err(anyhow::anyhow!(
+            "Expected a .wasm, .wat or .cwasm file. Pass --process to run {:?} as a service process.",
+            path
+        ))
     } else {
         Err(anyhow::anyhow!("Expected a file or directory."))
     }
 }
+
+/// Origins given with `--allowed-origin` replace those in the config file. Returns None,
+/// allowing any origin, if neither lists any.
+fn merge_allowed_origins(flags: Vec<String>, config: Option<Vec<String>>) -> Option<Vec<String>> {
+    if flags.is_empty() {
+        config
+    } else {
+        Some(flags)
+    }
+}
+
+/// Serves a core module, or a component if the file contains one. Components are always
+/// compiled on load, so `cache_dir` and `pooled_rooms` only apply to core modules.
+pub(crate) fn serve_module(
+    server: Server,
+    path: &Path,
+    cache_dir: Option<&str>,
+    pooled_rooms: Option<u32>,
+    capabilities: Capabilities,
+) -> anyhow::Result<()> {
+    if path.extension() == Some(OsStr::new("wasm")) && is_component(&fs::read(path)?) {
+        let component_factory = ComponentHostFactory::new(path)?.with_capabilities(capabilities);
+        return server.serve(component_factory).map_err(|e| e.into());
+    }
+
+    let host_factory = load_module(path, cache_dir, pooled_rooms)?.with_capabilities(capabilities);
+    server.serve(host_factory).map_err(|e| e.into())
+}
+
+fn load_module(
+    path: &Path,
+    cache_dir: Option<&str>,
+    pooled_rooms: Option<u32>,
+) -> anyhow::Result<WasmHostFactory> {
+    let mut loader = ModuleLoader::new();
+    if let Some(cache_dir) = cache_dir {
+        loader = loader.with_cache_dir(cache_dir);
+    }
+    if let Some(max_rooms) = pooled_rooms {
+        loader = loader.with_pooling_allocator(PoolingConfig {
+            max_rooms,
+            ..PoolingConfig::default()
+        });
+    }
+
+    if path.extension() == Some(OsStr::new(PRECOMPILED_EXTENSION)) {
+        // SAFETY: precompiled modules are produced by `stateroom build --precompile`, and
+        // are trusted as much as any other module the server is asked to run.
+        unsafe { loader.load_precompiled(path) }
+    } else {
+        loader.load(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::merge_allowed_origins;
+
+    #[test]
+    fn test_merge_allowed_origins() {
+        let origins = |origins: &[&str]| origins.iter().map(|o| o.to_string()).collect();
+
+        assert_eq!(merge_allowed_origins(Vec::new(), None), None);
+        assert_eq!(
+            merge_allowed_origins(Vec::new(), Some(origins(&["https://a.com"]))),
+            Some(origins(&["https://a.com"]))
+        );
+        assert_eq!(
+            merge_allowed_origins(
+                origins(&["https://b.com"]),
+                Some(origins(&["https://a.com"]))
+            ),
+            Some(origins(&["https://b.com"]))
+        );
+        assert_eq!(
+            merge_allowed_origins(origins(&["https://b.com"]), None),
+            Some(origins(&["https://b.com"]))
+        );
+    }
+}
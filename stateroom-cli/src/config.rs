@@ -1,4 +1,6 @@
 use serde::{Deserialize, Serialize};
+use stateroom_wasm_host::{Capabilities, GuestStdio};
+use std::{collections::BTreeMap, path::PathBuf, time::Duration};
 
 #[derive(Serialize, Deserialize, Debug, Default)]
 pub struct GlobalConfig {
@@ -28,6 +30,13 @@ pub struct StateroomConfig {
     /// Configuration for building the WebAssembly module to serve.
     #[serde(default)]
     pub service: ServiceConfig,
+
+    /// Origins that browsers may connect from, such as `https://example.com` or
+    /// `https://*.example.com`.
+    ///
+    /// If this is provided, WebSocket connections from other origins are rejected.
+    /// If it is omitted, connections from any origin are accepted.
+    pub allowed_origins: Option<Vec<String>>,
 }
 
 /// Configuration for generating a client-side WebAssembly module.
@@ -52,4 +61,82 @@ pub struct ServiceConfig {
     /// If this is empty, builds the package we are in (i.e. the package that
     /// `cargo build` builds.)
     pub package: Option<String>,
+
+    /// The target to build the package for. Defaults to `wasm32-wasip1`; use
+    /// `wasm32-wasip2` for services built as components.
+    pub target: Option<String>,
+
+    /// The WASI capabilities given to the service.
+    #[serde(default)]
+    pub wasi: WasiConfig,
+}
+
+impl ServiceConfig {
+    #[must_use]
+    pub fn target(&self) -> &str {
+        self.target.as_deref().unwrap_or("wasm32-wasip1")
+    }
+}
+
+/// The `[service.wasi]` section, which grants a service access to parts of the host.
+/// By default, a service can't see environment variables or the filesystem.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct WasiConfig {
+    /// Environment variables visible to the service.
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+
+    /// Host directories that the service may read, keyed by the path at which the
+    /// service sees them.
+    #[serde(default)]
+    pub read_only_dirs: BTreeMap<String, PathBuf>,
+
+    /// Where the service's standard output and error are written: `capture` (the
+    /// default) logs each line with the room ID, and `inherit` writes them to the
+    /// server's.
+    #[serde(default)]
+    pub stdio: StdioConfig,
+
+    /// The number of bytes of output per second that each room may write before the
+    /// rest is dropped, when output is captured.
+    pub output_bytes_per_second: Option<u32>,
+
+    /// The number of recent lines of output to keep for each room, when output is
+    /// captured.
+    pub recent_output_lines: Option<usize>,
+
+    /// If set, the clocks visible to the service are rounded down to a multiple of
+    /// this many milliseconds.
+    pub clock_resolution_ms: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum StdioConfig {
+    Inherit,
+    #[default]
+    Capture,
+}
+
+impl WasiConfig {
+    #[must_use]
+    pub fn capabilities(&self) -> Capabilities {
+        let defaults = Capabilities::default();
+
+        Capabilities {
+            env: self.env.clone(),
+            read_only_dirs: self.read_only_dirs.clone(),
+            stdio: match self.stdio {
+                StdioConfig::Inherit => GuestStdio::Inherit,
+                StdioConfig::Capture => GuestStdio::Capture,
+            },
+            output_bytes_per_second: self
+                .output_bytes_per_second
+                .unwrap_or(defaults.output_bytes_per_second),
+            recent_output_lines: self
+                .recent_output_lines
+                .unwrap_or(defaults.recent_output_lines),
+            clock_resolution: self.clock_resolution_ms.map(Duration::from_millis),
+        }
+    }
 }
@@ -0,0 +1,134 @@
+use anyhow::{anyhow, Result};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    convert::TryFrom,
+    io::{ErrorKind, Read, Write},
+    str::FromStr,
+};
+
+/// Frames larger than this are rejected rather than allocated, in case a process writes
+/// something other than frames to its standard output.
+const MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
+
+/// The encoding of frame payloads exchanged with a service process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encoding {
+    /// JSON, in serde's default representation of the message types.
+    #[default]
+    Json,
+
+    /// `bincode`, which is convenient for services written in Rust.
+    Bincode,
+}
+
+impl Encoding {
+    /// The value of the `STATEROOM_ENCODING` environment variable passed to processes.
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Json => "json",
+            Encoding::Bincode => "bincode",
+        }
+    }
+
+    pub(crate) fn encode(self, message: &impl Serialize) -> Result<Vec<u8>> {
+        match self {
+            Encoding::Json => Ok(serde_json::to_vec(message)?),
+            Encoding::Bincode => Ok(bincode::serialize(message)?),
+        }
+    }
+
+    pub(crate) fn decode<T: DeserializeOwned>(self, payload: &[u8]) -> Result<T> {
+        match self {
+            Encoding::Json => Ok(serde_json::from_slice(payload)?),
+            Encoding::Bincode => Ok(bincode::deserialize(payload)?),
+        }
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Encoding::Json),
+            "bincode" => Ok(Encoding::Bincode),
+            _ => Err(format!(
+                "Unknown encoding {:?}; expected json or bincode.",
+                s
+            )),
+        }
+    }
+}
+
+/// Writes a frame: a 4-byte little-endian length, followed by the payload.
+pub(crate) fn write_frame(writer: &mut impl Write, payload: &[u8]) -> Result<()> {
+    let len = u32::try_from(payload.len())?;
+    writer.write_all(&len.to_le_bytes())?;
+    writer.write_all(payload)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Reads a frame written by [write_frame], returning `None` at the end of the stream.
+pub(crate) fn read_frame(reader: &mut impl Read) -> Result<Option<Vec<u8>>> {
+    let mut len = [0; 4];
+    match reader.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(error) if error.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(error) => return Err(error.into()),
+    }
+
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_FRAME_SIZE {
+        return Err(anyhow!(
+            "Frame of {} bytes exceeds the maximum of {} bytes.",
+            len,
+            MAX_FRAME_SIZE
+        ));
+    }
+
+    let mut payload = vec![0; len];
+    reader.read_exact(&mut payload)?;
+    Ok(Some(payload))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_frame, write_frame, Encoding};
+    use stateroom::{ClientId, MessageFromProcess, MessageRecipient, MessageToProcess};
+
+    #[test]
+    fn test_frame_round_trip() {
+        let mut buffer = Vec::new();
+        write_frame(&mut buffer, b"hello").unwrap();
+        write_frame(&mut buffer, b"").unwrap();
+        assert_eq!(&buffer[..4], &5u32.to_le_bytes());
+
+        let mut reader = buffer.as_slice();
+        assert_eq!(read_frame(&mut reader).unwrap().unwrap(), b"hello");
+        assert_eq!(read_frame(&mut reader).unwrap().unwrap(), b"");
+        assert!(read_frame(&mut reader).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_json_encoding() {
+        let message = MessageToProcess::Connect {
+            client: ClientId(3),
+            protocol: None,
+        };
+        assert_eq!(
+            Encoding::Json.encode(&message).unwrap(),
+            br#"{"Connect":{"client":3,"protocol":null}}"#
+        );
+
+        let message: MessageFromProcess = Encoding::Json
+            .decode(br#"{"Message":{"recipient":{"Client":3},"message":{"Text":"hi"}}}"#)
+            .unwrap();
+        let MessageFromProcess::Message { recipient, message } = message else {
+            panic!("Expected a message.");
+        };
+        assert_eq!(recipient, MessageRecipient::Client(ClientId(3)));
+        assert_eq!(message.text(), Some("hi"));
+    }
+}
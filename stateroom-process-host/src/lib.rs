@@ -0,0 +1,11 @@
+//! This module provides a [stateroom::StateroomService] implementation that is backed by a
+//! child process. The process exchanges length-prefixed frames with the host over its
+//! standard input and output, so services can be written in any language.
+
+pub use encoding::Encoding;
+pub use process_host::ProcessHost;
+pub use process_host_factory::ProcessHostFactory;
+
+mod encoding;
+mod process_host;
+mod process_host_factory;
@@ -0,0 +1,314 @@
+use crate::{
+    encoding::{read_frame, write_frame},
+    Encoding, ProcessHostFactory,
+};
+use anyhow::{anyhow, Context, Result};
+use stateroom::{
+    ClientId, MessageFromProcess, MessagePayload, MessageToProcess, StateroomContext,
+    StateroomService,
+};
+use std::{
+    io::{BufReader, BufWriter},
+    path::Path,
+    process::{Child, ChildStdin, Command, Stdio},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+};
+use tokio::runtime::Handle;
+
+const ENV_ROOM_ID: &str = "STATEROOM_ROOM_ID";
+const ENV_ENCODING: &str = "STATEROOM_ENCODING";
+
+type Spawner = Box<dyn Fn() -> Result<RunningProcess> + Send + Sync>;
+
+/// Hosts a [stateroom::StateroomService] implemented by a child process.
+///
+/// The process is killed when the host is dropped. `stateroom-server` drops the host when
+/// its room closes, whether because the service closed the room or because it was empty
+/// for longer than the server's `empty_room_timeout`.
+pub struct ProcessHost {
+    room_id: String,
+    encoding: Encoding,
+    restart_on_crash: bool,
+    spawn: Spawner,
+    process: RunningProcess,
+}
+
+struct RunningProcess {
+    child: Child,
+    stdin: BufWriter<ChildStdin>,
+    /// Set by the reader thread once the process's output closes.
+    output_closed: Arc<AtomicBool>,
+    /// Set before the host kills the process, so that the reader thread doesn't report
+    /// it as a crash.
+    stopping: Arc<AtomicBool>,
+}
+
+impl RunningProcess {
+    /// Whether the process has exited, or closed its output so that it can no longer
+    /// send messages.
+    fn exited(&mut self) -> Result<bool> {
+        Ok(self.output_closed.load(Ordering::SeqCst) || self.child.try_wait()?.is_some())
+    }
+
+    fn kill(&mut self) {
+        self.stopping.store(true, Ordering::SeqCst);
+        // Killing a process that has already exited is not an error.
+        if let Err(error) = self.child.kill() {
+            tracing::warn!(?error, "Could not kill service process.");
+        }
+        let _ = self.child.wait();
+    }
+}
+
+impl ProcessHost {
+    pub(crate) fn new(
+        room_id: &str,
+        factory: &ProcessHostFactory,
+        context: Arc<impl StateroomContext>,
+    ) -> Result<Self> {
+        // Messages from the process are handled on a reader thread, which needs to be
+        // inside a runtime for the context to set timers.
+        let runtime = Handle::try_current().ok();
+        let encoding = factory.encoding;
+
+        let spawn: Spawner = {
+            let room_id = room_id.to_string();
+            let program = factory.program.clone();
+            let args = factory.args.clone();
+            Box::new(move || {
+                spawn_process(
+                    &room_id,
+                    &program,
+                    &args,
+                    encoding,
+                    runtime.clone(),
+                    context.clone(),
+                )
+            })
+        };
+
+        let process = spawn()?;
+
+        Ok(ProcessHost {
+            room_id: room_id.to_string(),
+            encoding,
+            restart_on_crash: factory.restart_on_crash,
+            spawn,
+            process,
+        })
+    }
+
+    fn write(&mut self, message: &MessageToProcess) -> Result<()> {
+        let payload = self.encoding.encode(message)?;
+        write_frame(&mut self.process.stdin, &payload)
+    }
+
+    /// Delivers a message to the process. If that fails, the room is closed.
+    fn deliver(&mut self, message: MessageToProcess, context: &impl StateroomContext) {
+        if let Err(error) = self.try_recv(message, context) {
+            tracing::error!(room_id=%self.room_id, ?error, "Closing room without a service process.");
+            context.close_room("Service process exited.");
+        }
+    }
+
+    /// Delivers a message to the process, restarting the process first if it has exited.
+    fn try_recv(
+        &mut self,
+        message: MessageToProcess,
+        context: &impl StateroomContext,
+    ) -> Result<()> {
+        if !self.process.exited()? {
+            match self.write(&message) {
+                Ok(()) => return Ok(()),
+                Err(error) => {
+                    tracing::warn!(room_id=%self.room_id, ?error, "Could not write to service process.")
+                }
+            }
+        }
+
+        if !self.restart_on_crash {
+            return Err(anyhow!(
+                "Service process for room {:?} exited.",
+                self.room_id
+            ));
+        }
+
+        let clients = self.restart(context)?;
+        if replayed_by_restart(&message, &clients) {
+            return Ok(());
+        }
+        self.write(&message)
+    }
+
+    /// Replaces the process with a new one, and replays `Init` and `Connect` for the
+    /// clients that are still connected. Returns the replayed clients.
+    fn restart(&mut self, context: &impl StateroomContext) -> Result<Vec<ClientId>> {
+        tracing::warn!(room_id=%self.room_id, "Service process exited; restarting it.");
+
+        self.process.kill();
+        self.process = (self.spawn)()?;
+
+        let clients = context.connected_clients();
+        self.write(&MessageToProcess::Init)?;
+        for &client in &clients {
+            self.write(&MessageToProcess::Connect {
+                client,
+                protocol: context.client_protocol(client),
+            })?;
+        }
+
+        Ok(clients)
+    }
+}
+
+impl Drop for ProcessHost {
+    fn drop(&mut self) {
+        self.process.kill();
+    }
+}
+
+impl StateroomService for ProcessHost {
+    fn init(&mut self, context: &impl StateroomContext) {
+        let message = MessageToProcess::Init;
+        self.deliver(message, context);
+    }
+
+    fn message(
+        &mut self,
+        sender: ClientId,
+        message: MessagePayload,
+        context: &impl StateroomContext,
+    ) {
+        let message = MessageToProcess::Message { sender, message };
+        self.deliver(message, context);
+    }
+
+    fn connect(&mut self, client: ClientId, context: &impl StateroomContext) {
+        let message = MessageToProcess::Connect {
+            client,
+            protocol: context.client_protocol(client),
+        };
+        self.deliver(message, context);
+    }
+
+    fn disconnect(&mut self, client: ClientId, context: &impl StateroomContext) {
+        let message = MessageToProcess::Disconnect { client };
+        self.deliver(message, context);
+    }
+
+    fn timer(&mut self, context: &impl StateroomContext) {
+        let message = MessageToProcess::Timer;
+        self.deliver(message, context);
+    }
+
+    fn rate_limited(&mut self, client: ClientId, context: &impl StateroomContext) {
+        let message = MessageToProcess::RateLimited { client };
+        self.deliver(message, context);
+    }
+
+    fn shutdown(&mut self, context: &impl StateroomContext) {
+        // The room is closing either way, so a failure here is only logged.
+        if let Err(error) = self.try_recv(MessageToProcess::Shutdown, context) {
+            tracing::warn!(room_id=%self.room_id, ?error, "Could not shut down service process.");
+        }
+    }
+}
+
+/// Whether a freshly restarted process has already seen the effect of a message, so that
+/// delivering it again would duplicate it.
+fn replayed_by_restart(message: &MessageToProcess, clients: &[ClientId]) -> bool {
+    match message {
+        MessageToProcess::Init => true,
+        // Timers belong to the process that set them. The reader thread also sets one to
+        // wake the room when the process exits, so that it is restarted without waiting
+        // for an event.
+        MessageToProcess::Timer => true,
+        MessageToProcess::Connect { client, .. } => clients.contains(client),
+        // A client that disconnected before the restart was never connected to the new process.
+        MessageToProcess::Disconnect { client } => !clients.contains(client),
+        _ => false,
+    }
+}
+
+/// Spawns a service process, and a thread that relays its messages to the context.
+fn spawn_process(
+    room_id: &str,
+    program: &Path,
+    args: &[String],
+    encoding: Encoding,
+    runtime: Option<Handle>,
+    context: Arc<impl StateroomContext>,
+) -> Result<RunningProcess> {
+    let mut child = Command::new(program)
+        .args(args)
+        .env(ENV_ROOM_ID, room_id)
+        .env(ENV_ENCODING, encoding.name())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()
+        .with_context(|| format!("Could not spawn service process {:?}", program))?;
+
+    let stdin = child
+        .stdin
+        .take()
+        .context("Service process has no stdin.")?;
+    let stdout = child
+        .stdout
+        .take()
+        .context("Service process has no stdout.")?;
+
+    let output_closed = Arc::new(AtomicBool::new(false));
+    let stopping = Arc::new(AtomicBool::new(false));
+
+    let room_id = room_id.to_string();
+    let reader_output_closed = output_closed.clone();
+    let reader_stopping = stopping.clone();
+    thread::Builder::new()
+        .name("stateroom-process-reader".to_string())
+        .spawn(move || {
+            let _guard = runtime.as_ref().map(Handle::enter);
+            let mut stdout = BufReader::new(stdout);
+
+            loop {
+                match read_frame(&mut stdout) {
+                    Ok(Some(payload)) => match encoding.decode::<MessageFromProcess>(&payload) {
+                        Ok(message) => message.dispatch(context.as_ref()),
+                        Err(error) => {
+                            tracing::warn!(
+                                room_id,
+                                ?error,
+                                "Could not decode message from service process."
+                            )
+                        }
+                    },
+                    Ok(None) => {
+                        tracing::info!(room_id, "Service process closed its output.");
+                        break;
+                    }
+                    Err(error) => {
+                        tracing::warn!(room_id, ?error, "Could not read from service process.");
+                        break;
+                    }
+                }
+            }
+
+            reader_output_closed.store(true, Ordering::SeqCst);
+            if !reader_stopping.load(Ordering::SeqCst) {
+                // Wake the room, which restarts the process (or closes the room) when it
+                // handles the timer, rather than when the next client event arrives.
+                context.set_timer(0);
+            }
+        })?;
+
+    Ok(RunningProcess {
+        child,
+        stdin: BufWriter::new(stdin),
+        output_closed,
+        stopping,
+    })
+}
@@ -0,0 +1,59 @@
+use crate::{Encoding, ProcessHost};
+use stateroom::{StateroomContext, StateroomServiceFactory};
+use std::{path::PathBuf, sync::Arc};
+
+/// Builds a [ProcessHost] for each room by spawning an executable.
+#[derive(Debug, Clone)]
+pub struct ProcessHostFactory {
+    pub(crate) program: PathBuf,
+    pub(crate) args: Vec<String>,
+    pub(crate) encoding: Encoding,
+    pub(crate) restart_on_crash: bool,
+}
+
+impl StateroomServiceFactory for ProcessHostFactory {
+    type Service = ProcessHost;
+    type Error = anyhow::Error;
+
+    fn build(
+        &self,
+        room_id: &str,
+        context: Arc<impl StateroomContext>,
+    ) -> Result<Self::Service, Self::Error> {
+        ProcessHost::new(room_id, self, context)
+    }
+}
+
+impl ProcessHostFactory {
+    /// Create a factory that runs the given executable, with no arguments, exchanging
+    /// JSON frames, and restarting the process if it exits.
+    pub fn new<P>(program: P) -> Self
+    where
+        P: Into<PathBuf>,
+    {
+        ProcessHostFactory {
+            program: program.into(),
+            args: Vec::new(),
+            encoding: Encoding::default(),
+            restart_on_crash: true,
+        }
+    }
+
+    #[must_use]
+    pub fn with_args(mut self, args: Vec<String>) -> Self {
+        self.args = args;
+        self
+    }
+
+    #[must_use]
+    pub fn with_encoding(mut self, encoding: Encoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    #[must_use]
+    pub fn with_restart_on_crash(mut self, restart_on_crash: bool) -> Self {
+        self.restart_on_crash = restart_on_crash;
+        self
+    }
+}
@@ -0,0 +1,294 @@
+//! Lifecycle tests, using a shell script as the service process. The script records its
+//! environment and PID, sends one frame to the host, and appends every frame it receives
+//! to a file shared by all of the room's processes. It keeps its output open as fd 3, since
+//! the host treats a process that closes its output as having exited.
+
+#![cfg(unix)]
+
+use stateroom::{
+    ClientId, MessagePayload, MessageRecipient, StateroomContext, StateroomService,
+    StateroomServiceFactory,
+};
+use stateroom_process_host::{ProcessHost, ProcessHostFactory};
+use stateroom_server::{
+    PanicPolicy, RateLimits, RoomMode, ServerState, ServiceExecutor, ServiceSpawner,
+};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+const SCRIPT: &str = r#"
+echo "$STATEROOM_ROOM_ID $STATEROOM_ENCODING" > "$1/env"
+echo $$ >> "$1/pids"
+printf '\033\000\000\000{"SetTimer":{"ms_delay":5}}'
+exec 3>&1 cat >> "$1/frames"
+"#;
+
+#[derive(Default)]
+struct RecordingContext {
+    clients: Vec<ClientId>,
+    timers: Mutex<Vec<u32>>,
+}
+
+impl StateroomContext for RecordingContext {
+    fn send_message(&self, _: impl Into<MessageRecipient>, _: impl Into<MessagePayload>) {}
+
+    fn set_timer(&self, ms_delay: u32) {
+        self.timers.lock().unwrap().push(ms_delay);
+    }
+
+    fn connected_clients(&self) -> Vec<ClientId> {
+        self.clients.clone()
+    }
+
+    fn client_protocol(&self, _: ClientId) -> Option<String> {
+        Some("chat".to_string())
+    }
+}
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir =
+        std::env::temp_dir().join(format!("stateroom-process-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn factory(dir: &Path) -> ProcessHostFactory {
+    ProcessHostFactory::new("sh").with_args(vec![
+        "-c".to_string(),
+        SCRIPT.to_string(),
+        "sh".to_string(),
+        dir.to_str().unwrap().to_string(),
+    ])
+}
+
+fn build(dir: &Path, context: &Arc<RecordingContext>) -> ProcessHost {
+    factory(dir).build("room", context.clone()).unwrap()
+}
+
+/// Waits up to ten seconds for the condition to hold.
+fn wait_until(mut condition: impl FnMut() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !condition() {
+        assert!(Instant::now() < deadline, "Timed out.");
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// Returns the PIDs of the processes spawned so far, waiting for at least `count`.
+fn pids(dir: &Path, count: usize) -> Vec<String> {
+    let mut pids = Vec::new();
+    wait_until(|| {
+        pids = fs::read_to_string(dir.join("pids"))
+            .unwrap_or_default()
+            .lines()
+            .map(str::to_string)
+            .collect();
+        pids.len() >= count
+    });
+    pids
+}
+
+/// Returns the frames received by the room's processes, waiting for at least `count`.
+fn frames(dir: &Path, count: usize) -> Vec<String> {
+    let mut frames = Vec::new();
+    wait_until(|| {
+        let data = fs::read(dir.join("frames")).unwrap_or_default();
+        let mut rest = data.as_slice();
+        frames.clear();
+        while rest.len() >= 4 {
+            let len = u32::from_le_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+            if rest.len() < 4 + len {
+                break;
+            }
+            frames.push(String::from_utf8(rest[4..4 + len].to_vec()).unwrap());
+            rest = &rest[4 + len..];
+        }
+        frames.len() >= count
+    });
+    frames
+}
+
+/// Returns the state of a process as reported by `ps`, which is empty once the process
+/// has been reaped and starts with `Z` while it is a zombie.
+fn process_state(pid: &str) -> String {
+    let output = Command::new("ps")
+        .args(["-o", "stat=", "-p", pid])
+        .output()
+        .unwrap();
+    String::from_utf8(output.stdout).unwrap().trim().to_string()
+}
+
+#[test]
+fn test_spawn() {
+    let dir = temp_dir("spawn");
+    let context = Arc::new(RecordingContext::default());
+    let mut host = build(&dir, &context);
+
+    host.init(context.as_ref());
+
+    assert_eq!(frames(&dir, 1), vec![r#""Init""#]);
+    assert_eq!(
+        fs::read_to_string(dir.join("env")).unwrap().trim(),
+        "room json"
+    );
+    // Frames written by the process are relayed to the context.
+    wait_until(|| *context.timers.lock().unwrap() == [5]);
+
+    drop(host);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_restart_replays_init_and_connect() {
+    let dir = temp_dir("restart");
+    let context = Arc::new(RecordingContext {
+        clients: vec![ClientId(1)],
+        ..RecordingContext::default()
+    });
+    let mut host = build(&dir, &context);
+
+    host.init(context.as_ref());
+    host.connect(ClientId(1), context.as_ref());
+    frames(&dir, 2);
+
+    let pid = pids(&dir, 1).remove(0);
+    Command::new("kill").args(["-9", &pid]).status().unwrap();
+    wait_until(|| process_state(&pid).is_empty() || process_state(&pid).starts_with('Z'));
+
+    host.message(ClientId(1), "hello".into(), context.as_ref());
+
+    assert_eq!(
+        frames(&dir, 5),
+        vec![
+            r#""Init""#,
+            r#"{"Connect":{"client":1,"protocol":"chat"}}"#,
+            r#""Init""#,
+            r#"{"Connect":{"client":1,"protocol":"chat"}}"#,
+            r#"{"Message":{"sender":1,"message":{"Text":"hello"}}}"#,
+        ]
+    );
+    assert_eq!(pids(&dir, 2).len(), 2);
+
+    drop(host);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_kill_on_drop() {
+    let dir = temp_dir("kill");
+    let context = Arc::new(RecordingContext::default());
+    let host = build(&dir, &context);
+
+    let pid = pids(&dir, 1).remove(0);
+    assert!(!process_state(&pid).is_empty());
+
+    drop(host);
+    assert_eq!(process_state(&pid), "");
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+fn server_state(factory: ProcessHostFactory, empty_room_timeout: Option<Duration>) -> ServerState {
+    ServerState::new(
+        factory,
+        ServiceSpawner::new(ServiceExecutor::DedicatedThread).unwrap(),
+        PanicPolicy::default(),
+        RoomMode::AutoCreate,
+        RateLimits::default(),
+        Vec::new(),
+        None,
+        empty_room_timeout,
+    )
+}
+
+#[test]
+fn test_restart_without_events() {
+    let dir = temp_dir("idle-restart");
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+
+    runtime.block_on(async {
+        let state = server_state(factory(&dir), None);
+        let room = state.room("room").await.unwrap();
+        let (_, _recv, _) = room.connect(None).await.unwrap();
+        let pid = pids(&dir, 1).remove(0);
+
+        // The crash is noticed although the room receives no further events.
+        let dir = dir.clone();
+        tokio::task::spawn_blocking(move || {
+            frames(&dir, 2);
+            Command::new("kill").args(["-9", &pid]).status().unwrap();
+            assert_eq!(pids(&dir, 2).len(), 2);
+
+            // Each process also receives the timer it sets.
+            let events = || -> Vec<String> {
+                frames(&dir, 0)
+                    .into_iter()
+                    .filter(|frame| frame != r#""Timer""#)
+                    .collect()
+            };
+            wait_until(|| events().len() == 4);
+            assert_eq!(
+                events(),
+                vec![
+                    r#""Init""#,
+                    r#"{"Connect":{"client":1,"protocol":null}}"#,
+                    r#""Init""#,
+                    r#"{"Connect":{"client":1,"protocol":null}}"#,
+                ]
+            );
+        })
+        .await
+        .unwrap();
+    });
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_exit_closes_room() {
+    let dir = temp_dir("exit");
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+
+    runtime.block_on(async {
+        let state = server_state(factory(&dir).with_restart_on_crash(false), None);
+        let room = state.room("room").await.unwrap();
+        let (_, _recv, _) = room.connect(None).await.unwrap();
+        let pid = pids(&dir, 1).remove(0);
+
+        Command::new("kill").args(["-9", &pid]).status().unwrap();
+        tokio::task::spawn_blocking(move || wait_until(|| state.room_count() == 0))
+            .await
+            .unwrap();
+        assert_eq!(pids(&dir, 1).len(), 1);
+    });
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_kill_when_room_closes() {
+    let dir = temp_dir("room-close");
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+
+    runtime.block_on(async {
+        let state = server_state(factory(&dir), Some(Duration::from_secs(1)));
+        let room = state.room("room").await.unwrap();
+        let (_, _recv, client) = room.connect(None).await.unwrap();
+        let pid = pids(&dir, 1).remove(0);
+
+        // The room closes once it has been empty for a second, which drops the host.
+        room.remove(&client).await;
+        drop(room);
+        tokio::task::spawn_blocking(move || wait_until(|| process_state(&pid).is_empty()))
+            .await
+            .unwrap();
+    });
+
+    fs::remove_dir_all(&dir).unwrap();
+}
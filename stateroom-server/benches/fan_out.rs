@@ -0,0 +1,82 @@
+//! Measures the cost of broadcasting a message to a large room.
+//!
+//! Throughput is reported per recipient, so the figures approximate the cost of
+//! delivering one message to one client as the payload grows.
+
+use axum::extract::ws::Message;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use stateroom::{
+    ClientId, DefaultStateroomFactory, MessagePayload, MessageRecipient, StateroomContext,
+    StateroomService,
+};
+use stateroom_server::{
+    Event, PanicPolicy, RateLimits, RoomMode, ServerState, ServiceExecutor, ServiceSpawner,
+};
+use tokio::runtime::Runtime;
+
+const CLIENTS: usize = 1_000;
+const PAYLOAD_SIZES: [usize; 3] = [64, 4 * 1024, 64 * 1024];
+
+#[derive(Default)]
+struct BroadcastService;
+
+impl StateroomService for BroadcastService {
+    fn message(&mut self, _: ClientId, message: MessagePayload, ctx: &impl StateroomContext) {
+        ctx.send_message(MessageRecipient::Broadcast, message);
+    }
+}
+
+fn fan_out(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("fan_out");
+    group.throughput(Throughput::Elements(CLIENTS as u64));
+
+    for size in PAYLOAD_SIZES {
+        let (state, mut receivers) = runtime.block_on(async {
+            let spawner = ServiceSpawner::new(ServiceExecutor::Inline).unwrap();
+            let state = ServerState::new(
+                DefaultStateroomFactory::<BroadcastService>::default(),
+                spawner,
+                PanicPolicy::default(),
+                RoomMode::AutoCreate,
+                RateLimits::default(),
+                Vec::new(),
+                None,
+                None,
+            )
+            .room("")
+            .await
+            .unwrap();
+            let mut receivers = Vec::with_capacity(CLIENTS);
+            for _ in 0..CLIENTS {
+                receivers.push(state.connect(None).await.unwrap().1);
+            }
+            (state, receivers)
+        });
+        let payload = vec![0u8; size];
+
+        group.bench_with_input(BenchmarkId::from_parameter(size), &size, |b, _| {
+            b.iter(|| {
+                runtime.block_on(async {
+                    state
+                        .inbound_sender
+                        .send(Event::Message {
+                            client: ClientId(1),
+                            message: Message::Binary(payload.clone().into()),
+                        })
+                        .await
+                        .unwrap();
+
+                    for receiver in &mut receivers {
+                        receiver.recv().await.unwrap();
+                    }
+                })
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, fan_out);
+criterion_main!(benches);
@@ -0,0 +1,106 @@
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+};
+use tokio::runtime::{Builder, Handle, Runtime};
+
+/// Determines where each room's service loop runs.
+///
+/// Services are called synchronously, so a service that does CPU-heavy or blocking
+/// work in a handler holds up whatever thread it runs on. Running services outside
+/// of the tokio runtime keeps them from starving socket IO for other rooms.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ServiceExecutor {
+    /// Run each room's service as a task on the server's tokio runtime.
+    #[default]
+    Inline,
+
+    /// Run each room's service on its own OS thread.
+    DedicatedThread,
+
+    /// Run services on a fixed pool of OS threads, with rooms assigned to threads
+    /// in round-robin order.
+    ThreadPool { threads: usize },
+}
+
+/// Spawns room service loops according to a [ServiceExecutor].
+///
+/// Events are delivered to services over the same channel regardless of executor;
+/// only the thread that drives the receiving end differs.
+#[derive(Clone)]
+pub struct ServiceSpawner {
+    inner: SpawnerInner,
+}
+
+#[derive(Clone)]
+enum SpawnerInner {
+    Inline,
+    DedicatedThread,
+    ThreadPool {
+        shards: Arc<Vec<Handle>>,
+        next_shard: Arc<AtomicUsize>,
+    },
+}
+
+impl ServiceSpawner {
+    /// Create a spawner, starting any threads that the executor requires.
+    pub fn new(executor: ServiceExecutor) -> std::io::Result<Self> {
+        let inner = match executor {
+            ServiceExecutor::Inline => SpawnerInner::Inline,
+            ServiceExecutor::DedicatedThread => SpawnerInner::DedicatedThread,
+            ServiceExecutor::ThreadPool { threads } => {
+                let shards = (0..threads.max(1))
+                    .map(|i| {
+                        let runtime = service_runtime()?;
+                        let handle = runtime.handle().clone();
+                        thread::Builder::new()
+                            .name(format!("stateroom-service-{}", i))
+                            .spawn(move || runtime.block_on(std::future::pending::<()>()))?;
+                        Ok(handle)
+                    })
+                    .collect::<std::io::Result<Vec<_>>>()?;
+
+                SpawnerInner::ThreadPool {
+                    shards: Arc::new(shards),
+                    next_shard: Arc::new(AtomicUsize::new(0)),
+                }
+            }
+        };
+
+        Ok(ServiceSpawner { inner })
+    }
+
+    /// Run a service loop to completion on the executor.
+    pub fn spawn<F>(&self, future: F) -> std::io::Result<()>
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        match &self.inner {
+            SpawnerInner::Inline => {
+                tokio::spawn(future);
+            }
+            SpawnerInner::DedicatedThread => {
+                let runtime = service_runtime()?;
+                thread::Builder::new()
+                    .name("stateroom-service".to_string())
+                    .spawn(move || runtime.block_on(future))?;
+            }
+            SpawnerInner::ThreadPool { shards, next_shard } => {
+                let shard = next_shard.fetch_add(1, Ordering::Relaxed) % shards.len();
+                shards[shard].spawn(future);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Builds a single-threaded runtime for driving services (and their timers) off the
+/// main runtime.
+fn service_runtime() -> std::io::Result<Runtime> {
+    Builder::new_current_thread().enable_all().build()
+}
@@ -1,23 +1,54 @@
-use crate::server::Event;
 use axum::{
-    extract::{ws::WebSocket, State, WebSocketUpgrade},
-    routing::get,
-    Router,
+    body::Bytes,
+    extract::{
+        ws::{CloseFrame, Message, WebSocket},
+        Path, State, WebSocketUpgrade,
+    },
+    http::{HeaderValue, StatusCode},
+    middleware,
+    response::IntoResponse,
+    routing::{get, post},
+    Json, Router,
 };
-use server::ServerState;
-use stateroom::StateroomServiceFactory;
+use origin::{check_origin, cors_layer};
+use rate_limit::{is_message_too_big, RateLimiter};
+use serde::Serialize;
+use stateroom::{ClientId, HistoryLimit, OutputSource, StateroomServiceFactory};
 use std::{
     net::{IpAddr, SocketAddr},
     sync::Arc,
-    time::Duration,
+    time::{Duration, UNIX_EPOCH},
+};
+use tokio::{
+    net::TcpListener,
+    select,
+    sync::mpsc::{Receiver, Sender},
 };
-use tokio::{net::TcpListener, select};
 use tower_http::services::ServeDir;
 
+pub use executor::{ServiceExecutor, ServiceSpawner};
+pub use origin::AllowedOrigins;
+pub use rate_limit::{
+    RateLimitAction, RateLimits, TokenBucket, CLOSE_CODE_MESSAGE_TOO_BIG,
+    CLOSE_CODE_POLICY_VIOLATION,
+};
+pub use server::{
+    Event, PanicPolicy, Room, RoomError, RoomMode, ServerState, ServerStateroomContext,
+    CLOSE_CODE_INTERNAL_ERROR, CLOSE_CODE_NORMAL,
+};
+
+mod executor;
+mod origin;
+mod rate_limit;
 mod server;
 
 const DEFAULT_IP: &str = "0.0.0.0";
 
+/// Settings for serving a [StateroomServiceFactory] over WebSockets.
+///
+/// WebSocket messages are always sent uncompressed. The WebSocket implementation that
+/// axum is built on (tungstenite) rejects frames with the RSV1 bit set and has no hook
+/// for extensions, so `permessage-deflate` can't be negotiated until it supports one.
 #[derive(Debug)]
 pub struct Server {
     /// The duration of time between server-initiated WebSocket heartbeats.
@@ -41,6 +72,52 @@ pub struct Server {
 
     /// A local filesystem path to serve from /client, or None (default).
     pub client_path: Option<String>,
+
+    /// Where each room's service runs. Defaults to [ServiceExecutor::Inline].
+    pub executor: ServiceExecutor,
+
+    /// What to do when a room's service panics. Defaults to closing every client
+    /// connection with close code 1011.
+    pub panic_policy: PanicPolicy,
+
+    /// Whether connecting to an unknown room creates it. Defaults to [RoomMode::AutoCreate].
+    pub room_mode: RoomMode,
+
+    /// Whether to expose `POST /rooms` for creating rooms with generated IDs. Defaults to false.
+    pub room_api: bool,
+
+    /// Whether to expose `GET /admin/rooms/{room_id}/output`, which returns the recent
+    /// output of a room's service. Defaults to false.
+    ///
+    /// Admin endpoints are not authenticated, so only enable them if the server can't be
+    /// reached by untrusted clients, or add an authenticating layer to the router
+    /// returned by [Server::into_router].
+    pub admin_api: bool,
+
+    /// Origins that browsers may open WebSocket connections and fetch static files from,
+    /// or None (default) to allow any origin.
+    ///
+    /// WebSocket upgrades and `POST /rooms` requests from other origins are rejected with
+    /// HTTP 403, and static file routes answer CORS requests from allowed origins.
+    pub allowed_origins: Option<AllowedOrigins>,
+
+    /// Limits on the size and rate of messages that clients may send. Defaults to no limits.
+    pub rate_limits: RateLimits,
+
+    /// WebSocket subprotocols (`Sec-WebSocket-Protocol`) that the server supports, in order
+    /// of preference. Of those a client requests, the factory picks one with
+    /// [StateroomServiceFactory::select_protocol]. Defaults to none.
+    pub subprotocols: Vec<String>,
+
+    /// How many of each room's broadcasts to replay to clients that join the room, unless
+    /// the factory chooses with [StateroomServiceFactory::history_limit]. Defaults to
+    /// None, which keeps no history.
+    pub history: Option<HistoryLimit>,
+
+    /// How long a room may go without clients before it is closed and its service is
+    /// dropped, counting from when the room is created or its last client leaves.
+    /// Defaults to None, which keeps rooms until their service closes them.
+    pub empty_room_timeout: Option<Duration>,
 }
 
 impl Default for Server {
@@ -52,6 +129,16 @@ impl Default for Server {
             ip: DEFAULT_IP.to_string(),
             static_path: None,
             client_path: None,
+            executor: ServiceExecutor::default(),
+            panic_policy: PanicPolicy::default(),
+            room_mode: RoomMode::default(),
+            room_api: false,
+            admin_api: false,
+            allowed_origins: None,
+            rate_limits: RateLimits::default(),
+            subprotocols: Vec::new(),
+            history: None,
+            empty_room_timeout: None,
         }
     }
 }
@@ -98,30 +185,158 @@ impl Server {
         self
     }
 
-    /// Start a server given a [StateroomService].
+    #[must_use]
+    pub fn with_executor(mut self, executor: ServiceExecutor) -> Self {
+        self.executor = executor;
+        self
+    }
+
+    #[must_use]
+    pub fn with_panic_policy(mut self, panic_policy: PanicPolicy) -> Self {
+        self.panic_policy = panic_policy;
+        self
+    }
+
+    #[must_use]
+    pub fn with_room_mode(mut self, room_mode: RoomMode) -> Self {
+        self.room_mode = room_mode;
+        self
+    }
+
+    #[must_use]
+    pub fn with_room_api(mut self, room_api: bool) -> Self {
+        self.room_api = room_api;
+        self
+    }
+
+    #[must_use]
+    pub fn with_admin_api(mut self, admin_api: bool) -> Self {
+        self.admin_api = admin_api;
+        self
+    }
+
+    #[must_use]
+    pub fn with_allowed_origins(mut self, allowed_origins: Option<AllowedOrigins>) -> Self {
+        self.allowed_origins = allowed_origins;
+        self
+    }
+
+    #[must_use]
+    pub fn with_rate_limits(mut self, rate_limits: RateLimits) -> Self {
+        self.rate_limits = rate_limits;
+        self
+    }
+
+    #[must_use]
+    pub fn with_subprotocols(mut self, subprotocols: Vec<String>) -> Self {
+        self.subprotocols = subprotocols;
+        self
+    }
+
+    #[must_use]
+    pub fn with_history(mut self, history: Option<HistoryLimit>) -> Self {
+        self.history = history;
+        self
+    }
+
+    #[must_use]
+    pub fn with_empty_room_timeout(mut self, empty_room_timeout: Option<Duration>) -> Self {
+        self.empty_room_timeout = empty_room_timeout;
+        self
+    }
+
+    /// Build an [axum::Router] that serves the Stateroom endpoints, without binding a listener.
     ///
-    /// This function blocks until the server is terminated. While it is running, the following
-    /// endpoints are available:
-    /// - `/` (GET): return HTTP 200 if the server is running (useful as a baseline status check)
+    /// This allows Stateroom to be nested or merged into an existing axum application.
+    /// Layers added by the application wrap these routes, so they run before a WebSocket
+    /// upgrade is accepted. The router provides the following endpoints:
+    /// - `/status` (GET): return HTTP 200 if the server is running (a liveness check).
+    /// - `/ready` (GET): return HTTP 200 if the server is accepting connections, or 503.
+    /// - `/status/details` (GET): return JSON describing the loaded module, uptime and rooms.
     /// - `/ws` (GET): initiate a WebSocket connection to the stateroom service.
-    pub async fn serve_async(self, factory: impl StateroomServiceFactory) -> std::io::Result<()> {
-        let server_state = Arc::new(ServerState::new(factory));
+    /// - `/ws/{room_id}` (GET): initiate a WebSocket connection to the given room.
+    /// - `/rooms` (POST): create a room with a generated ID, if [Server::room_api] is set.
+    /// - `/admin/rooms/{room_id}/output` (GET): return JSON with the recent output of the
+    ///   room's service, if [Server::admin_api] is set.
+    pub fn into_router(self, factory: impl StateroomServiceFactory) -> std::io::Result<Router> {
+        let (app, server_state) = self.build_router(factory)?;
+
+        // The host application owns the listener, so the module being loaded is all
+        // that readiness can reflect.
+        server_state.set_ready(true);
 
-        let mut app = Router::new()
+        Ok(app)
+    }
+
+    fn build_router(
+        self,
+        factory: impl StateroomServiceFactory,
+    ) -> std::io::Result<(Router, Arc<ServerState>)> {
+        let spawner = ServiceSpawner::new(self.executor)?;
+        let server_state = Arc::new(ServerState::new(
+            factory,
+            spawner,
+            self.panic_policy,
+            self.room_mode,
+            self.rate_limits,
+            self.subprotocols,
+            self.history,
+            self.empty_room_timeout,
+        ));
+
+        let allowed_origins = self.allowed_origins.map(Arc::new);
+
+        let mut router = Router::new()
             .route("/ws", get(serve_websocket))
-            .with_state(server_state);
+            .route("/ws/{room_id}", get(serve_room_websocket));
+
+        if self.room_api {
+            router = router.route("/rooms", post(create_room));
+        }
+
+        // Applies to the routes above, which browsers may be tricked into requesting
+        // from other sites.
+        if let Some(allowed_origins) = &allowed_origins {
+            router = router.route_layer(middleware::from_fn_with_state(
+                allowed_origins.clone(),
+                check_origin,
+            ));
+        }
+
+        router = router
+            .route("/status", get(status))
+            .route("/ready", get(ready))
+            .route("/status/details", get(status_details));
+
+        if self.admin_api {
+            router = router.route("/admin/rooms/{room_id}/output", get(room_output));
+        }
+
+        let mut app = router.with_state(server_state.clone());
 
         if let Some(static_path) = self.static_path {
-            app = app.nest_service("/", ServeDir::new(static_path));
+            app = app.fallback_service(static_files(static_path, &allowed_origins));
         }
 
         if let Some(client_path) = self.client_path {
-            app = app.nest_service("/client", ServeDir::new(client_path));
+            app = app.nest_service("/client", static_files(client_path, &allowed_origins));
         }
 
+        Ok((app, server_state))
+    }
+
+    /// Start a server given a [StateroomService].
+    ///
+    /// This function blocks until the server is terminated. While it is running, the
+    /// endpoints described in [Server::into_router] are available.
+    pub async fn serve_async(self, factory: impl StateroomServiceFactory) -> std::io::Result<()> {
         let ip = self.ip.parse::<IpAddr>().unwrap();
         let addr = SocketAddr::new(ip, self.port);
+
+        let (app, server_state) = self.build_router(factory)?;
+
         let listener = TcpListener::bind(&addr).await?;
+        server_state.set_ready(true);
         axum::serve(listener, app).await?;
 
         Ok(())
@@ -129,10 +344,8 @@ impl Server {
 
     /// Start a server given a [StateroomService].
     ///
-    /// This function blocks until the server is terminated. While it is running, the following
-    /// endpoints are available:
-    /// - `/` (GET): return HTTP 200 if the server is running (useful as a baseline status check)
-    /// - `/ws` (GET): initiate a WebSocket connection to the stateroom service.
+    /// This function blocks until the server is terminated. While it is running, the
+    /// endpoints described in [Server::into_router] are available.
     pub fn serve(self, factory: impl StateroomServiceFactory) -> std::io::Result<()> {
         tokio::runtime::Builder::new_multi_thread()
             .enable_all()
@@ -142,33 +355,458 @@ impl Server {
     }
 }
 
+/// Serves files from a directory, answering CORS requests from allowed origins.
+fn static_files(path: String, allowed_origins: &Option<Arc<AllowedOrigins>>) -> Router {
+    let router = Router::new().fallback_service(ServeDir::new(path));
+
+    match allowed_origins {
+        Some(allowed_origins) => router.layer(cors_layer(allowed_origins.clone())),
+        None => router,
+    }
+}
+
+async fn status() -> &'static str {
+    "ok"
+}
+
+async fn ready(State(state): State<Arc<ServerState>>) -> axum::response::Response {
+    if state.is_ready() {
+        "ok".into_response()
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "not ready").into_response()
+    }
+}
+
+#[derive(Serialize)]
+struct StatusDetails<'a> {
+    status: &'a str,
+    ready: bool,
+    module_hash: Option<&'a str>,
+    api_version: Option<i32>,
+    uptime_seconds: u64,
+    room_count: usize,
+}
+
+async fn status_details(State(state): State<Arc<ServerState>>) -> axum::response::Response {
+    let service_info = state.service_info();
+
+    Json(StatusDetails {
+        status: "ok",
+        ready: state.is_ready(),
+        module_hash: service_info.module_hash.as_deref(),
+        api_version: service_info.api_version,
+        uptime_seconds: state.uptime().as_secs(),
+        room_count: state.room_count(),
+    })
+    .into_response()
+}
+
+#[derive(Serialize)]
+struct RoomOutputLine {
+    stream: &'static str,
+    text: String,
+    timestamp_ms: u128,
+}
+
+/// Returns the recent output of a room's service, oldest first, or HTTP 404 if the room
+/// isn't open or the service doesn't keep its output.
+async fn room_output(
+    State(state): State<Arc<ServerState>>,
+    Path(room_id): Path<String>,
+) -> axum::response::Response {
+    let Some(lines) = state.recent_output(&room_id) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let lines: Vec<RoomOutputLine> = lines
+        .into_iter()
+        .map(|line| RoomOutputLine {
+            stream: match line.source {
+                OutputSource::Stdout => "stdout",
+                OutputSource::Stderr => "stderr",
+            },
+            text: line.text,
+            timestamp_ms: line
+                .time
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis(),
+        })
+        .collect();
+
+    Json(lines).into_response()
+}
+
+#[derive(Serialize)]
+struct CreatedRoom {
+    room_id: String,
+}
+
+/// Creates a room with a generated ID, passing the request body to the factory as
+/// the room's configuration.
+pub async fn create_room(
+    State(state): State<Arc<ServerState>>,
+    config: Bytes,
+) -> axum::response::Response {
+    match state.create_room(&config).await {
+        Ok(room_id) => (StatusCode::CREATED, Json(CreatedRoom { room_id })).into_response(),
+        Err(error) => {
+            tracing::warn!(kind=?error.kind, reason=%error.reason, "Could not create room.");
+            error.status_code().into_response()
+        }
+    }
+}
+
 pub async fn serve_websocket(
     ws: WebSocketUpgrade,
     State(state): State<Arc<ServerState>>,
 ) -> axum::response::Response {
-    ws.on_upgrade(move |socket| handle_socket(socket, state))
+    upgrade_to_room(ws, state, String::new()).await
 }
 
-async fn handle_socket(mut socket: WebSocket, state: Arc<ServerState>) {
-    let (send, mut recv, client_id) = state.connect();
+pub async fn serve_room_websocket(
+    ws: WebSocketUpgrade,
+    Path(room_id): Path<String>,
+    State(state): State<Arc<ServerState>>,
+) -> axum::response::Response {
+    upgrade_to_room(ws, state, room_id).await
+}
+
+async fn upgrade_to_room(
+    mut ws: WebSocketUpgrade,
+    state: Arc<ServerState>,
+    room_id: String,
+) -> axum::response::Response {
+    // Build the room before accepting the upgrade, so that clients get an HTTP error
+    // rather than a socket to a room that does not exist.
+    let room = match state.room(&room_id).await {
+        Ok(room) => room,
+        Err(error) => {
+            tracing::warn!(room_id, kind=?error.kind, reason=%error.reason, "Could not build room.");
+            return error.status_code().into_response();
+        }
+    };
+
+    let protocol = state.select_protocol(
+        &room_id,
+        ws.requested_protocols()
+            .filter_map(|protocol| protocol.to_str().ok()),
+    );
+    if let Some(protocol) = &protocol {
+        match HeaderValue::from_str(protocol) {
+            Ok(value) => ws.set_selected_protocol(value),
+            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+    }
+
+    let rate_limits = state.rate_limits().clone();
+    let ws = match rate_limits.max_message_size {
+        Some(max_message_size) => ws
+            .max_message_size(max_message_size)
+            .max_frame_size(max_message_size),
+        None => ws,
+    };
+
+    ws.on_upgrade(move |socket| handle_socket(socket, state, room_id, room, protocol, rate_limits))
+}
+
+/// Joins a client to the room it was upgraded for. If that room closed in the meantime,
+/// for example because it was left empty, the client joins its replacement instead.
+async fn join_room(
+    state: &ServerState,
+    room_id: &str,
+    room: Arc<Room>,
+    protocol: Option<String>,
+) -> Option<(Arc<Room>, Sender<Event>, Receiver<Message>, ClientId)> {
+    if let Some((send, recv, client_id)) = room.connect(protocol.clone()).await {
+        return Some((room, send, recv, client_id));
+    }
+
+    let room = state.room(room_id).await.ok()?;
+    let (send, recv, client_id) = room.connect(protocol).await?;
+    Some((room, send, recv, client_id))
+}
+
+async fn handle_socket(
+    mut socket: WebSocket,
+    state: Arc<ServerState>,
+    room_id: String,
+    room: Arc<Room>,
+    protocol: Option<String>,
+    rate_limits: RateLimits,
+) {
+    let Some((room, send, mut recv, client_id)) = join_room(&state, &room_id, room, protocol).await
+    else {
+        let _ = socket
+            .send(Message::Close(Some(CloseFrame {
+                code: CLOSE_CODE_INTERNAL_ERROR,
+                reason: "Room is not available.".into(),
+            })))
+            .await;
+        return;
+    };
+
+    let mut limiter = RateLimiter::for_client(&rate_limits);
 
     loop {
         select! {
             msg = recv.recv() => {
                 match msg {
-                    Some(msg) => socket.send(msg).await.unwrap(),
+                    Some(msg) => {
+                        if socket.send(msg).await.is_err() {
+                            break;
+                        }
+                    }
                     None => break,
                 }
             },
             msg = socket.recv() => {
                 match msg {
-                    Some(Ok(msg)) => send.send(Event::Message { client: client_id, message: msg }).await.unwrap(),
-                    Some(Err(_)) => todo!("Error receiving message from client."),
+                    Some(Ok(msg)) => {
+                        let size = match &msg {
+                            Message::Text(text) => Some(text.len()),
+                            Message::Binary(bytes) => Some(bytes.len()),
+                            _ => None,
+                        };
+
+                        let event = match size {
+                            Some(size) if !limiter.check(size, || room.try_accept_message(size)) => {
+                                match rate_limits.action {
+                                    RateLimitAction::Drop => {
+                                        tracing::debug!(client=?client_id, "Dropping rate-limited message.");
+                                        continue;
+                                    }
+                                    RateLimitAction::Notify => Event::RateLimited { client: client_id },
+                                    RateLimitAction::Disconnect => {
+                                        tracing::info!(client=?client_id, "Disconnecting rate-limited client.");
+                                        let _ = socket
+                                            .send(Message::Close(Some(CloseFrame {
+                                                code: CLOSE_CODE_POLICY_VIOLATION,
+                                                reason: "Rate limit exceeded.".into(),
+                                            })))
+                                            .await;
+                                        break;
+                                    }
+                                }
+                            }
+                            _ => Event::Message { client: client_id, message: msg },
+                        };
+
+                        if send.send(event).await.is_err() {
+                            break;
+                        }
+                    }
+                    Some(Err(error)) => {
+                        tracing::info!(client=?client_id, ?error, "Error receiving message from client.");
+                        if is_message_too_big(error) {
+                            let _ = socket
+                                .send(Message::Close(Some(CloseFrame {
+                                    code: CLOSE_CODE_MESSAGE_TOO_BIG,
+                                    reason: "Message too big.".into(),
+                                })))
+                                .await;
+                        }
+                        break;
+                    }
                     None => break,
                 }
             }
         }
     }
 
-    state.remove(&client_id);
+    room.remove(&client_id).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{AllowedOrigins, RateLimits, Server, CLOSE_CODE_MESSAGE_TOO_BIG};
+    use axum::Router;
+    use stateroom::{
+        BuildErrorKind, DefaultStateroomFactory, StateroomContext, StateroomService,
+        StateroomServiceFactory,
+    };
+    use std::{
+        io::{Read, Write},
+        net::{SocketAddr, TcpStream},
+        sync::Arc,
+    };
+    use tokio::net::TcpListener;
+
+    const UPGRADE_REQUEST: &str = "GET /ws/room HTTP/1.1\r\n\
+                                   Host: localhost\r\n\
+                                   Connection: Upgrade\r\n\
+                                   Upgrade: websocket\r\n\
+                                   Sec-WebSocket-Version: 13\r\n\
+                                   Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n";
+
+    #[derive(Default)]
+    struct NoopService;
+
+    impl StateroomService for NoopService {}
+
+    /// Fails to build every room with the given kind of error.
+    struct FailingFactory(BuildErrorKind);
+
+    impl StateroomServiceFactory for FailingFactory {
+        type Service = NoopService;
+        type Error = BuildErrorKind;
+
+        fn build(
+            &self,
+            _: &str,
+            _: Arc<impl StateroomContext>,
+        ) -> Result<Self::Service, Self::Error> {
+            Err(self.0)
+        }
+
+        fn error_kind(&self, error: &Self::Error) -> BuildErrorKind {
+            *error
+        }
+    }
+
+    async fn serve(router: Router) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router).await });
+        address
+    }
+
+    /// Serves the router, sends it a raw HTTP request, and returns the response status.
+    async fn status(router: Router, request: String) -> u16 {
+        let address = serve(router).await;
+
+        let response = tokio::task::spawn_blocking(move || {
+            let mut stream = TcpStream::connect(address).unwrap();
+            stream.write_all(request.as_bytes()).unwrap();
+            let mut response = [0; 12];
+            stream.read_exact(&mut response).unwrap();
+            response
+        })
+        .await
+        .unwrap();
+
+        // The status line starts with `HTTP/1.1 NNN`.
+        std::str::from_utf8(&response[9..12])
+            .unwrap()
+            .parse()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_build_errors_map_to_status() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let cases = [
+            (BuildErrorKind::NotFound, 404),
+            (BuildErrorKind::Forbidden, 403),
+            (BuildErrorKind::Unavailable, 503),
+            (BuildErrorKind::Internal, 500),
+        ];
+
+        runtime.block_on(async {
+            for (kind, expected) in cases {
+                let server = Server {
+                    room_api: true,
+                    ..Server::default()
+                };
+                let router = server.into_router(FailingFactory(kind)).unwrap();
+
+                assert_eq!(
+                    status(router.clone(), UPGRADE_REQUEST.to_string()).await,
+                    expected,
+                    "{:?}",
+                    kind
+                );
+
+                let create = "POST /rooms HTTP/1.1\r\n\
+                              Host: localhost\r\n\
+                              Content-Length: 0\r\n\r\n";
+                assert_eq!(
+                    status(router, create.to_string()).await,
+                    expected,
+                    "{:?}",
+                    kind
+                );
+            }
+        });
+    }
+
+    #[test]
+    fn test_room_api_checks_origin() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let create = |origin: &str| {
+            format!(
+                "POST /rooms HTTP/1.1\r\n\
+                 Host: localhost\r\n\
+                 Origin: {}\r\n\
+                 Content-Length: 0\r\n\r\n",
+                origin
+            )
+        };
+
+        runtime.block_on(async {
+            let server = Server::default()
+                .with_room_api(true)
+                .with_allowed_origins(Some(AllowedOrigins::new(["https://example.com"])));
+            let router = server
+                .into_router(DefaultStateroomFactory::<NoopService>::default())
+                .unwrap();
+
+            assert_eq!(
+                status(router.clone(), create("https://example.com")).await,
+                201
+            );
+            assert_eq!(status(router, create("https://evil.com")).await, 403);
+        });
+    }
+
+    #[test]
+    fn test_oversized_message_closes_with_1009() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+
+        let code = runtime.block_on(async {
+            let server = Server {
+                rate_limits: RateLimits {
+                    max_message_size: Some(10),
+                    ..RateLimits::default()
+                },
+                ..Server::default()
+            };
+            let router = server
+                .into_router(DefaultStateroomFactory::<NoopService>::default())
+                .unwrap();
+            let address = serve(router).await;
+
+            tokio::task::spawn_blocking(move || {
+                let mut stream = TcpStream::connect(address).unwrap();
+                stream.write_all(UPGRADE_REQUEST.as_bytes()).unwrap();
+                let mut response = Vec::new();
+                while !response.ends_with(b"\r\n\r\n") {
+                    let mut byte = [0];
+                    stream.read_exact(&mut byte).unwrap();
+                    response.push(byte[0]);
+                }
+                assert!(response.starts_with(b"HTTP/1.1 101"));
+
+                // A masked binary frame with an all-zero mask and a 20-byte payload.
+                let mut frame = vec![0x82, 0x80 | 20, 0, 0, 0, 0];
+                frame.extend([0; 20]);
+                stream.write_all(&frame).unwrap();
+
+                // Skip any frames, such as heartbeat pings, until the close frame.
+                loop {
+                    let mut header = [0; 2];
+                    stream.read_exact(&mut header).unwrap();
+                    let mut payload = vec![0; usize::from(header[1] & 0x7f)];
+                    stream.read_exact(&mut payload).unwrap();
+                    if header[0] & 0x0f == 0x8 {
+                        return u16::from_be_bytes([payload[0], payload[1]]);
+                    }
+                }
+            })
+            .await
+            .unwrap()
+        });
+
+        assert_eq!(code, CLOSE_CODE_MESSAGE_TOO_BIG);
+    }
 }
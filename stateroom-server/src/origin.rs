@@ -0,0 +1,155 @@
+use axum::{
+    extract::{Request, State},
+    http::{header::ORIGIN, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::sync::Arc;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+/// A list of origins that browsers may connect from.
+///
+/// Each pattern is one of:
+/// - `*`, which allows any origin.
+/// - An origin such as `https://example.com` or `http://localhost:8080`, which must match exactly.
+/// - An origin with a wildcard subdomain such as `https://*.example.com`, which allows any
+///   subdomain of `example.com` (but not `example.com` itself).
+///
+/// Patterns without a scheme (like `*.example.com`) match any scheme. Comparisons are
+/// case-insensitive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AllowedOrigins {
+    patterns: Vec<String>,
+}
+
+impl AllowedOrigins {
+    pub fn new<S: AsRef<str>>(patterns: impl IntoIterator<Item = S>) -> Self {
+        AllowedOrigins {
+            patterns: patterns
+                .into_iter()
+                .map(|p| p.as_ref().trim().trim_end_matches('/').to_ascii_lowercase())
+                .collect(),
+        }
+    }
+
+    /// Returns true if the given `Origin` header value is allowed.
+    #[must_use]
+    pub fn allows(&self, origin: &str) -> bool {
+        let origin = origin.trim().to_ascii_lowercase();
+        self.patterns
+            .iter()
+            .any(|pattern| pattern_matches(pattern, &origin))
+    }
+}
+
+/// Middleware that rejects requests whose `Origin` header is not allowed with HTTP 403.
+///
+/// Requests without an `Origin` header are not made by browsers, so they are not subject
+/// to cross-site WebSocket hijacking and are let through.
+pub(crate) async fn check_origin(
+    State(allowed_origins): State<Arc<AllowedOrigins>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if let Some(origin) = request.headers().get(ORIGIN) {
+        let allowed = origin
+            .to_str()
+            .map(|origin| allowed_origins.allows(origin))
+            .unwrap_or(false);
+
+        if !allowed {
+            tracing::warn!(?origin, "Rejecting request from disallowed origin.");
+            return StatusCode::FORBIDDEN.into_response();
+        }
+    }
+
+    next.run(request).await
+}
+
+/// A CORS policy for static file routes that allows the same origins as WebSocket upgrades.
+pub(crate) fn cors_layer(allowed_origins: Arc<AllowedOrigins>) -> CorsLayer {
+    CorsLayer::new()
+        .allow_origin(AllowOrigin::predicate(move |origin: &HeaderValue, _| {
+            origin
+                .to_str()
+                .map(|origin| allowed_origins.allows(origin))
+                .unwrap_or(false)
+        }))
+        .allow_methods([Method::GET, Method::HEAD])
+}
+
+fn pattern_matches(pattern: &str, origin: &str) -> bool {
+    if pattern == "*" {
+        return true;
+    }
+
+    let Some((origin_scheme, origin_authority)) = origin.split_once("://") else {
+        return false;
+    };
+
+    let pattern_authority = match pattern.split_once("://") {
+        Some((scheme, authority)) if scheme == origin_scheme => authority,
+        Some(_) => return false,
+        None => pattern,
+    };
+
+    let (pattern_host, pattern_port) = split_port(pattern_authority);
+    let (origin_host, origin_port) = split_port(origin_authority);
+
+    if pattern_port != origin_port {
+        return false;
+    }
+
+    match pattern_host.strip_prefix('*') {
+        Some(suffix) if suffix.starts_with('.') => {
+            origin_host.len() > suffix.len() && origin_host.ends_with(suffix)
+        }
+        _ => pattern_host == origin_host,
+    }
+}
+
+fn split_port(authority: &str) -> (&str, Option<&str>) {
+    match authority.rsplit_once(':') {
+        Some((host, port)) if !port.is_empty() && port.bytes().all(|b| b.is_ascii_digit()) => {
+            (host, Some(port))
+        }
+        _ => (authority, None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AllowedOrigins;
+
+    #[test]
+    fn test_exact_origins() {
+        let origins = AllowedOrigins::new(["https://example.com", "http://localhost:8080"]);
+
+        assert!(origins.allows("https://example.com"));
+        assert!(origins.allows("HTTPS://Example.com"));
+        assert!(origins.allows("http://localhost:8080"));
+
+        assert!(!origins.allows("http://example.com"));
+        assert!(!origins.allows("https://example.com:8443"));
+        assert!(!origins.allows("https://www.example.com"));
+        assert!(!origins.allows("http://localhost:8081"));
+        assert!(!origins.allows("null"));
+    }
+
+    #[test]
+    fn test_wildcard_origins() {
+        let origins = AllowedOrigins::new(["https://*.example.com", "*.test.dev"]);
+
+        assert!(origins.allows("https://app.example.com"));
+        assert!(origins.allows("https://a.b.example.com"));
+        assert!(!origins.allows("https://example.com"));
+        assert!(!origins.allows("https://evilexample.com"));
+        assert!(!origins.allows("http://app.example.com"));
+
+        assert!(origins.allows("http://app.test.dev"));
+        assert!(origins.allows("https://app.test.dev"));
+        assert!(!origins.allows("https://app.test.dev:8080"));
+
+        assert!(AllowedOrigins::new(["*"]).allows("https://anything.at.all"));
+    }
+}
@@ -0,0 +1,277 @@
+use std::{str::FromStr, time::Instant};
+use tungstenite::error::{CapacityError, Error as WsError};
+
+/// WebSocket close code sent to clients that are disconnected for exceeding a rate limit.
+pub const CLOSE_CODE_POLICY_VIOLATION: u16 = 1008;
+
+/// WebSocket close code sent to clients that send a message larger than
+/// [RateLimits::max_message_size].
+pub const CLOSE_CODE_MESSAGE_TOO_BIG: u16 = 1009;
+
+/// Limits on the size and rate of messages that clients may send.
+///
+/// Rates are enforced with token buckets that allow bursts of up to one second's worth
+/// of traffic, or [RateLimits::burst_bytes] for the byte limits. Every limit is disabled
+/// (`None`) by default.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RateLimits {
+    /// The largest message, in bytes, that a client may send. Larger messages close the
+    /// connection with close code 1009.
+    pub max_message_size: Option<usize>,
+
+    /// The number of messages per second that each client may send.
+    pub client_messages_per_second: Option<u32>,
+
+    /// The number of bytes per second that each client may send.
+    pub client_bytes_per_second: Option<u32>,
+
+    /// The number of messages per second that all clients of a room may send combined.
+    pub room_messages_per_second: Option<u32>,
+
+    /// The number of bytes per second that all clients of a room may send combined.
+    pub room_bytes_per_second: Option<u32>,
+
+    /// The number of bytes that the byte limits allow in a single burst, if that is more
+    /// than one second's worth. Defaults to `max_message_size`, so that any message that
+    /// isn't too large is eventually accepted. Without either, a message larger than a
+    /// byte limit's per-second rate is always rejected.
+    pub burst_bytes: Option<u32>,
+
+    /// What to do with messages that exceed a rate limit.
+    pub action: RateLimitAction,
+}
+
+impl RateLimits {
+    fn byte_bucket(&self, per_second: u32) -> TokenBucket {
+        let burst = self
+            .burst_bytes
+            .or_else(|| {
+                self.max_message_size
+                    .map(|size| u32::try_from(size).unwrap_or(u32::MAX))
+            })
+            .unwrap_or(0);
+        TokenBucket::with_capacity(per_second, per_second.max(burst))
+    }
+}
+
+/// Determines what happens to a message that exceeds a rate limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RateLimitAction {
+    /// Silently drop the message.
+    #[default]
+    Drop,
+
+    /// Drop the message and call [stateroom::StateroomService::rate_limited] on the service.
+    Notify,
+
+    /// Drop the message and disconnect the client with close code 1008.
+    Disconnect,
+}
+
+impl FromStr for RateLimitAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop" => Ok(RateLimitAction::Drop),
+            "notify" => Ok(RateLimitAction::Notify),
+            "disconnect" => Ok(RateLimitAction::Disconnect),
+            _ => Err(format!(
+                "Unknown rate limit action {:?}; expected drop, notify, or disconnect.",
+                s
+            )),
+        }
+    }
+}
+
+/// A token bucket that refills continuously at a fixed rate, up to a capacity that
+/// defaults to one second's worth of tokens.
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    #[must_use]
+    pub fn new(per_second: u32) -> Self {
+        Self::with_capacity(per_second, per_second)
+    }
+
+    /// Creates a full bucket that holds up to `capacity` tokens.
+    #[must_use]
+    pub fn with_capacity(per_second: u32, capacity: u32) -> Self {
+        Self::new_at(per_second, capacity, Instant::now())
+    }
+
+    fn new_at(per_second: u32, capacity: u32, now: Instant) -> Self {
+        TokenBucket {
+            rate: f64::from(per_second),
+            capacity: f64::from(capacity),
+            tokens: f64::from(capacity),
+            last_refill: now,
+        }
+    }
+
+    /// Returns whether the bucket holds at least `cost` tokens, without taking them.
+    pub fn has(&mut self, cost: u32) -> bool {
+        self.has_at(cost, Instant::now())
+    }
+
+    /// Takes `cost` tokens from the bucket if it holds enough, returning whether it did.
+    pub fn try_take(&mut self, cost: u32) -> bool {
+        self.try_take_at(cost, Instant::now())
+    }
+
+    fn has_at(&mut self, cost: u32, now: Instant) -> bool {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last_refill = now;
+        self.tokens >= f64::from(cost)
+    }
+
+    fn try_take_at(&mut self, cost: u32, now: Instant) -> bool {
+        if self.has_at(cost, now) {
+            self.tokens -= f64::from(cost);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Enforces a message rate and a byte rate together, for either a single connection
+/// or a whole room.
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    messages: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+}
+
+impl RateLimiter {
+    pub fn for_client(limits: &RateLimits) -> Self {
+        RateLimiter {
+            messages: limits.client_messages_per_second.map(TokenBucket::new),
+            bytes: limits
+                .client_bytes_per_second
+                .map(|rate| limits.byte_bucket(rate)),
+        }
+    }
+
+    pub fn for_room(limits: &RateLimits) -> Self {
+        RateLimiter {
+            messages: limits.room_messages_per_second.map(TokenBucket::new),
+            bytes: limits
+                .room_bytes_per_second
+                .map(|rate| limits.byte_bucket(rate)),
+        }
+    }
+
+    /// Returns whether a message of the given size is within both limits, and then
+    /// whether `also` accepts it. Tokens are only taken if everything accepts the
+    /// message, so a rejected message doesn't count against either limit.
+    pub fn check(&mut self, size: usize, also: impl FnOnce() -> bool) -> bool {
+        let size = u32::try_from(size).unwrap_or(u32::MAX);
+        let allowed = self.messages.as_mut().is_none_or(|b| b.has(1))
+            && self.bytes.as_mut().is_none_or(|b| b.has(size))
+            && also();
+        if allowed {
+            if let Some(messages) = &mut self.messages {
+                messages.try_take(1);
+            }
+            if let Some(bytes) = &mut self.bytes {
+                bytes.try_take(size);
+            }
+        }
+        allowed
+    }
+}
+
+/// Returns whether a receive error means the client sent a message larger than
+/// [RateLimits::max_message_size].
+pub(crate) fn is_message_too_big(error: axum::Error) -> bool {
+    matches!(
+        error.into_inner().downcast_ref::<WsError>(),
+        Some(WsError::Capacity(CapacityError::MessageTooLong { .. }))
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RateLimiter, RateLimits, TokenBucket};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_token_bucket() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new_at(10, 10, start);
+
+        // The bucket starts full, allowing a burst of one second's worth of tokens.
+        for _ in 0..10 {
+            assert!(bucket.try_take_at(1, start));
+        }
+        assert!(!bucket.try_take_at(1, start));
+
+        // Tokens refill in proportion to elapsed time.
+        let later = start + Duration::from_millis(500);
+        assert!(bucket.try_take_at(5, later));
+        assert!(!bucket.try_take_at(1, later));
+
+        // The bucket never holds more than its capacity.
+        let much_later = later + Duration::from_secs(60);
+        assert!(!bucket.try_take_at(11, much_later));
+        assert!(bucket.try_take_at(10, much_later));
+    }
+
+    #[test]
+    fn test_token_bucket_capacity() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new_at(10, 100, start);
+
+        assert!(!bucket.try_take_at(101, start));
+        assert!(bucket.try_take_at(100, start));
+
+        // Refilling the whole capacity takes ten seconds at ten tokens per second.
+        assert!(!bucket.has_at(100, start + Duration::from_secs(5)));
+        assert!(bucket.try_take_at(100, start + Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn test_burst_defaults_to_max_message_size() {
+        let limits = RateLimits {
+            max_message_size: Some(1000),
+            client_bytes_per_second: Some(100),
+            ..RateLimits::default()
+        };
+        assert!(RateLimiter::for_client(&limits).check(1000, || true));
+
+        let limits = RateLimits {
+            burst_bytes: Some(500),
+            ..limits
+        };
+        let mut limiter = RateLimiter::for_client(&limits);
+        assert!(!limiter.check(1000, || true));
+        assert!(limiter.check(500, || true));
+    }
+
+    #[test]
+    fn test_rejected_message_takes_no_tokens() {
+        let limits = RateLimits {
+            client_messages_per_second: Some(1),
+            client_bytes_per_second: Some(10),
+            ..RateLimits::default()
+        };
+        let mut limiter = RateLimiter::for_client(&limits);
+
+        // Too many bytes: the message token must not be spent.
+        assert!(!limiter.check(11, || true));
+        // Rejected by the room: neither token is spent.
+        assert!(!limiter.check(10, || false));
+        assert!(limiter.check(10, || true));
+        assert!(!limiter.check(0, || true));
+    }
+}
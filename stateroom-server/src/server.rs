@@ -1,46 +1,161 @@
-use axum::extract::ws::Message;
-use dashmap::DashMap;
+use crate::{
+    executor::ServiceSpawner,
+    rate_limit::{RateLimiter, RateLimits},
+};
+use axum::{
+    extract::ws::{CloseFrame, Message, Utf8Bytes},
+    http::StatusCode,
+};
+use bytes::Bytes;
+use dashmap::{mapref::entry::Entry, DashMap};
+use futures_util::future::BoxFuture;
+use rand::{distributions::Alphanumeric, Rng};
+#[cfg(feature = "bytes")]
+use stateroom::ByteString;
 use stateroom::{
-    ClientId, MessagePayload, MessageRecipient, StateroomContext, StateroomService,
-    StateroomServiceFactory,
+    BuildErrorKind, ClientId, HistoryBuffer, HistoryLimit, MessagePayload, MessageRecipient,
+    OutputLine, ServiceInfo, StateroomContext, StateroomService, StateroomServiceFactory,
 };
 use std::{
-    sync::{atomic::AtomicU32, Arc, Mutex},
-    time::Duration,
+    any::Any,
+    collections::HashSet,
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::{Duration, Instant},
 };
-use tokio::{
-    sync::mpsc::{Receiver, Sender},
-    task::JoinHandle,
+use tokio::sync::{
+    mpsc::{error::TrySendError, Receiver, Sender, WeakSender},
+    oneshot,
 };
 
+/// WebSocket close code sent to clients when their room stops unexpectedly.
+pub const CLOSE_CODE_INTERNAL_ERROR: u16 = 1011;
+
+/// WebSocket close code sent to clients when their room's service closes the room.
+pub const CLOSE_CODE_NORMAL: u16 = 1000;
+
+/// The longest reason that fits in a WebSocket close frame, in bytes.
+const MAX_CLOSE_REASON_LENGTH: usize = 123;
+
+/// The number of messages that can be queued for a client, in addition to those replayed
+/// from the room's history when it joins.
+const CLIENT_QUEUE_LENGTH: usize = 100;
+
+/// The recent broadcasts of a room, shared by the room and its context.
+type History = Arc<Mutex<HistoryBuffer<Message>>>;
+
+/// Determines what happens to a room when its service panics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PanicPolicy {
+    /// Replace the service with a fresh one from the factory, and replay `connect`
+    /// for every client that is still connected.
+    Restart,
+
+    /// Close every client connection with the given WebSocket close code and stop the room.
+    Close { code: u16 },
+}
+
+impl Default for PanicPolicy {
+    fn default() -> Self {
+        PanicPolicy::Close {
+            code: CLOSE_CODE_INTERNAL_ERROR,
+        }
+    }
+}
+
 /// A [StateroomContext] implementation for [StateroomService]s hosted in the
 /// context of a [ServiceActor].
 pub struct ServerStateroomContext {
     senders: Arc<DashMap<ClientId, Sender<Message>>>,
-    event_sender: Arc<Sender<Event>>,
+    groups: Arc<DashMap<String, HashSet<ClientId>>>,
+    protocols: Arc<DashMap<ClientId, String>>,
+    /// Weak, so that a room whose clients and [Room] are all gone stops its service.
+    event_sender: WeakSender<Event>,
     timer_handle: Mutex<Option<tokio::task::JoinHandle<()>>>,
+    close_reason: Mutex<Option<String>>,
+    history: Option<History>,
+    accepting_clients: Arc<Mutex<bool>>,
 }
 
 impl ServerStateroomContext {
+    fn send_to_client(&self, client_id: &ClientId, message: Message) {
+        if let Some(sender) = self.senders.get(client_id) {
+            log_send_error(client_id, sender.try_send(message));
+        } else {
+            tracing::error!(?client_id, "No sender for client.");
+        }
+    }
+
+    /// Sends a close frame to every connected client and forgets them, so that their
+    /// sockets are closed once the frame is written.
+    fn close_all(&self, code: u16, reason: &str) {
+        let message = Message::Close(Some(CloseFrame {
+            code,
+            reason: truncate_reason(reason).into(),
+        }));
+        for sender in self.senders.iter() {
+            log_send_error(sender.key(), sender.try_send(message.clone()));
+        }
+        self.senders.clear();
+        self.groups.clear();
+        self.protocols.clear();
+    }
+
+    fn remove_from_groups(&self, client: &ClientId) {
+        self.groups.retain(|_, members| {
+            members.remove(client);
+            !members.is_empty()
+        });
+    }
+
+    /// Delivers an encoded message to each of its recipients.
+    ///
+    /// [Message] payloads are reference-counted, so fanning a message out to many
+    /// clients clones a pointer per recipient rather than the message body.
     pub fn try_send(&self, recipient: MessageRecipient, message: Message) {
         match recipient {
             MessageRecipient::Broadcast => {
+                // Hold the history lock while fanning out, so that a client that is
+                // joining gets this message either from the history or live, not both.
+                let _history = self.history.as_deref().map(|history| {
+                    let mut history = history.lock().expect("history lock poisoned");
+                    history.push(message.clone());
+                    history
+                });
                 for sender in self.senders.iter() {
-                    sender.value().try_send(message.clone()).unwrap();
+                    log_send_error(sender.key(), sender.try_send(message.clone()));
                 }
             }
             MessageRecipient::EveryoneExcept(skip_client_id) => {
                 for sender in self.senders.iter() {
                     if sender.key() != &skip_client_id {
-                        sender.try_send(message.clone()).unwrap();
+                        log_send_error(sender.key(), sender.try_send(message.clone()));
                     }
                 }
             }
             MessageRecipient::Client(client_id) => {
-                if let Some(sender) = self.senders.get(&client_id) {
-                    sender.try_send(message).unwrap();
-                } else {
-                    tracing::error!(?client_id, "No sender for client.");
+                self.send_to_client(&client_id, message);
+            }
+            MessageRecipient::Clients(client_ids) => {
+                for client_id in &client_ids {
+                    self.send_to_client(client_id, message.clone());
+                }
+            }
+            MessageRecipient::EveryoneExceptMany(skip_client_ids) => {
+                for sender in self.senders.iter() {
+                    if !skip_client_ids.contains(sender.key()) {
+                        log_send_error(sender.key(), sender.try_send(message.clone()));
+                    }
+                }
+            }
+            MessageRecipient::Group(group) => {
+                if let Some(members) = self.groups.get(&group) {
+                    for client_id in members.iter() {
+                        self.send_to_client(client_id, message.clone());
+                    }
                 }
             }
         }
@@ -54,9 +169,16 @@ impl StateroomContext for ServerStateroomContext {
         message: impl Into<MessagePayload>,
     ) {
         let message: MessagePayload = message.into();
+        // Convert the payload into a message once, without copying, so that it
+        // can be shared between all recipients. This works whether or not the `bytes`
+        // feature of `stateroom` is enabled, since it may be enabled by another crate.
+        #[allow(clippy::useless_conversion)]
         let message: Message = match message {
-            MessagePayload::Text(s) => Message::Text(s),
-            MessagePayload::Bytes(b) => Message::Binary(b),
+            MessagePayload::Text(s) => Message::Text(
+                Utf8Bytes::try_from(Bytes::from(s.into_bytes()))
+                    .expect("text payload is valid UTF-8"),
+            ),
+            MessagePayload::Bytes(b) => Message::Binary(Bytes::from(b)),
         };
         self.try_send(recipient.into(), message);
     }
@@ -65,7 +187,10 @@ impl StateroomContext for ServerStateroomContext {
         let sender = self.event_sender.clone();
         let handle = tokio::spawn(async move {
             tokio::time::sleep(Duration::from_millis(ms_delay as u64)).await;
-            sender.send(Event::Timer).await.unwrap();
+            // The room may have stopped while the timer was pending.
+            if let Some(sender) = sender.upgrade() {
+                let _ = sender.send(Event::Timer).await;
+            }
         });
 
         let mut c = self
@@ -77,95 +202,1445 @@ impl StateroomContext for ServerStateroomContext {
         }
         *c = Some(handle);
     }
+
+    fn join_group(&self, client: ClientId, group: &str) {
+        // A client that already left would never be removed from the group again.
+        // Clients are also removed from their groups when the service handles their
+        // disconnect, in case they leave between this check and the insert.
+        if !self.senders.contains_key(&client) {
+            return;
+        }
+        self.groups
+            .entry(group.to_string())
+            .or_default()
+            .insert(client);
+    }
+
+    fn leave_group(&self, client: ClientId, group: &str) {
+        self.groups.remove_if_mut(group, |_, members| {
+            members.remove(&client);
+            members.is_empty()
+        });
+    }
+
+    fn connected_clients(&self) -> Vec<ClientId> {
+        let mut clients: Vec<ClientId> = self.senders.iter().map(|e| *e.key()).collect();
+        clients.sort();
+        clients
+    }
+
+    fn client_count(&self) -> usize {
+        self.senders.len()
+    }
+
+    fn client_protocol(&self, client: ClientId) -> Option<String> {
+        self.protocols.get(&client).map(|protocol| protocol.clone())
+    }
+
+    fn close_room(&self, reason: &str) {
+        self.close_reason
+            .lock()
+            .expect("close reason lock poisoned")
+            .get_or_insert_with(|| reason.to_string());
+
+        // The room checks for a close request after each event, so this only matters
+        // if the service called this from outside of a handler.
+        if let Some(sender) = self.event_sender.upgrade() {
+            let _ = sender.try_send(Event::Close);
+        }
+    }
 }
 
-#[derive(Debug)]
+type RoomBuilder =
+    Box<dyn Fn(String, Vec<u8>) -> BoxFuture<'static, Result<Room, RoomError>> + Send + Sync>;
+type ProtocolSelector = Box<dyn Fn(&str, &[String]) -> Option<String> + Send + Sync>;
+type OutputReader = Box<dyn Fn(&str) -> Option<Vec<OutputLine>> + Send + Sync>;
+type RoomMap = DashMap<String, Arc<Room>>;
+
+/// Length of the room IDs generated by [ServerState::create_room]. Each character is
+/// drawn from 62 alphanumerics, for about 143 bits of entropy.
+const GENERATED_ROOM_ID_LENGTH: usize = 24;
+
+/// Determines how rooms come into existence.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RoomMode {
+    /// Connecting to a room that does not exist creates it.
+    #[default]
+    AutoCreate,
+
+    /// Rooms must be created explicitly (for example, through `POST /rooms`), and
+    /// connections to unknown room IDs are rejected with HTTP 404.
+    Explicit,
+}
+
+/// Shared state of a server, which tracks its rooms by ID and builds them on demand.
 pub struct ServerState {
-    pub handle: JoinHandle<()>,
+    rooms: Arc<RoomMap>,
+    build_room: RoomBuilder,
+    subprotocols: Vec<String>,
+    select_protocol: ProtocolSelector,
+    recent_output: OutputReader,
+    room_mode: RoomMode,
+    rate_limits: RateLimits,
+    history: Option<HistoryLimit>,
+    service_info: ServiceInfo,
+    started: Instant,
+    ready: AtomicBool,
+}
+
+impl ServerState {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        factory: impl StateroomServiceFactory,
+        spawner: ServiceSpawner,
+        panic_policy: PanicPolicy,
+        room_mode: RoomMode,
+        rate_limits: RateLimits,
+        subprotocols: Vec<String>,
+        history: Option<HistoryLimit>,
+        empty_room_timeout: Option<Duration>,
+    ) -> Self {
+        let service_info = factory.service_info();
+        let factory = Arc::new(factory);
+        let room_rate_limits = rate_limits.clone();
+        let selector_factory = factory.clone();
+        let output_factory = factory.clone();
+        let rooms = Arc::new(RoomMap::new());
+        let room_map = Arc::downgrade(&rooms);
+
+        ServerState {
+            rooms,
+            subprotocols,
+            select_protocol: Box::new(move |room_id, protocols| {
+                selector_factory.select_protocol(room_id, protocols)
+            }),
+            recent_output: Box::new(move |room_id| output_factory.recent_output(room_id)),
+            build_room: Box::new(move |room_id, config| {
+                let factory = factory.clone();
+                let spawner = spawner.clone();
+                let room_map = room_map.clone();
+                let rate_limits = room_rate_limits.clone();
+                Box::pin(async move {
+                    Room::new(
+                        factory,
+                        &room_id,
+                        &config,
+                        &spawner,
+                        panic_policy,
+                        &rate_limits,
+                        history,
+                        empty_room_timeout,
+                        room_map,
+                    )
+                    .await
+                })
+            }),
+            room_mode,
+            rate_limits,
+            history,
+            service_info,
+            started: Instant::now(),
+            ready: AtomicBool::new(false),
+        }
+    }
+
+    /// Limits on the messages that clients may send.
+    pub fn rate_limits(&self) -> &RateLimits {
+        &self.rate_limits
+    }
+
+    /// The history kept for rooms whose factory doesn't choose a limit. See
+    /// [StateroomServiceFactory::history_limit].
+    pub fn history(&self) -> Option<HistoryLimit> {
+        self.history
+    }
+
+    /// Negotiates the subprotocol for a client connecting to a room, given the
+    /// subprotocols the client requested.
+    ///
+    /// Candidates are the server's supported subprotocols that the client requested, in
+    /// the server's order of preference; the factory picks one of them.
+    pub fn select_protocol<'a>(
+        &self,
+        room_id: &str,
+        requested: impl IntoIterator<Item = &'a str>,
+    ) -> Option<String> {
+        let requested: HashSet<&str> = requested.into_iter().collect();
+        let candidates: Vec<String> = self
+            .subprotocols
+            .iter()
+            .filter(|protocol| requested.contains(protocol.as_str()))
+            .cloned()
+            .collect();
+
+        if candidates.is_empty() {
+            return None;
+        }
+
+        (self.select_protocol)(room_id, &candidates)
+            .filter(|protocol| candidates.contains(protocol))
+    }
+
+    /// Describes the service that rooms on this server run.
+    pub fn service_info(&self) -> &ServiceInfo {
+        &self.service_info
+    }
+
+    /// The most recent output of the service in a room, if the factory keeps it. See
+    /// [StateroomServiceFactory::recent_output].
+    pub fn recent_output(&self, room_id: &str) -> Option<Vec<OutputLine>> {
+        (self.recent_output)(room_id)
+    }
+
+    /// The time elapsed since the server state was created.
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// The number of rooms that are currently running.
+    pub fn room_count(&self) -> usize {
+        self.rooms.iter().filter(|room| room.is_open()).count()
+    }
+
+    /// Whether the server is ready to accept connections.
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::Relaxed)
+    }
+
+    /// Marks the server as ready (or not) to accept connections.
+    pub fn set_ready(&self, ready: bool) {
+        self.ready.store(ready, Ordering::Relaxed);
+    }
+
+    /// Returns the room with the given ID.
+    ///
+    /// A room that has stopped (for example, after its service panicked) is rebuilt with
+    /// the configuration it was created with. Unknown rooms are created if the server is
+    /// in [RoomMode::AutoCreate], and rejected otherwise. Rooms are discarded once their
+    /// last client leaves.
+    pub async fn room(&self, room_id: &str) -> Result<Arc<Room>, RoomError> {
+        let config = match self.rooms.get(room_id) {
+            Some(room) if room.is_open() => return Ok(room.clone()),
+            Some(room) => room.config.clone(),
+            None if self.room_mode == RoomMode::AutoCreate => Vec::new(),
+            None => return Err(RoomError::not_found(room_id)),
+        };
+
+        // The map isn't locked while the service is built, since that runs service code.
+        let room = Arc::new((self.build_room)(room_id.to_string(), config).await?);
+
+        match self.rooms.entry(room_id.to_string()) {
+            // Another connection built the room first. Dropping this room's copy stops
+            // its service, since no client has joined it.
+            Entry::Occupied(entry) if entry.get().is_open() => Ok(entry.get().clone()),
+            Entry::Occupied(mut entry) => {
+                entry.insert(room.clone());
+                Ok(room)
+            }
+            // The room was closed while it was being rebuilt.
+            Entry::Vacant(_) if self.room_mode == RoomMode::Explicit => {
+                Err(RoomError::not_found(room_id))
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(room.clone());
+                Ok(room)
+            }
+        }
+    }
+
+    /// Creates a room with a randomly generated, unguessable ID, and returns the ID.
+    ///
+    /// The configuration is passed to [StateroomServiceFactory::build_with_config].
+    pub async fn create_room(&self, config: &[u8]) -> Result<String, RoomError> {
+        loop {
+            let room_id: String = rand::thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(GENERATED_ROOM_ID_LENGTH)
+                .map(char::from)
+                .collect();
+            if self.rooms.contains_key(&room_id) {
+                continue;
+            }
+
+            let room = (self.build_room)(room_id.clone(), config.to_vec()).await?;
+            if let Entry::Vacant(entry) = self.rooms.entry(room_id.clone()) {
+                entry.insert(Arc::new(room));
+                return Ok(room_id);
+            }
+        }
+    }
+}
+
+/// An error encountered while building a room, which is reported to the client
+/// whose connection attempt triggered it.
+#[derive(Debug)]
+pub struct RoomError {
+    pub kind: BuildErrorKind,
+    pub reason: String,
+}
+
+impl RoomError {
+    fn not_found(room_id: &str) -> Self {
+        RoomError {
+            kind: BuildErrorKind::NotFound,
+            reason: format!("Room {:?} does not exist.", room_id),
+        }
+    }
+
+    /// The HTTP status code that corresponds to this error.
+    #[must_use]
+    pub fn status_code(&self) -> StatusCode {
+        match self.kind {
+            BuildErrorKind::NotFound => StatusCode::NOT_FOUND,
+            BuildErrorKind::Forbidden => StatusCode::FORBIDDEN,
+            BuildErrorKind::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+            BuildErrorKind::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// A running instance of a service, and the clients connected to it.
+#[derive(Debug)]
+pub struct Room {
+    /// The configuration the room was created with.
+    pub config: Vec<u8>,
     pub inbound_sender: Sender<Event>,
     pub senders: Arc<DashMap<ClientId, Sender<Message>>>,
+    pub groups: Arc<DashMap<String, HashSet<ClientId>>>,
+    pub protocols: Arc<DashMap<ClientId, String>>,
     pub next_client_id: AtomicU32,
+    rate_limiter: Mutex<RateLimiter>,
+    history: Option<History>,
+    accepting_clients: Arc<Mutex<bool>>,
 }
 
 #[derive(Debug)]
 pub enum Event {
-    Message { client: ClientId, message: Message },
-    Join { client: ClientId },
-    Leave { client: ClientId },
+    Message {
+        client: ClientId,
+        message: Message,
+    },
+    Join {
+        client: ClientId,
+    },
+    Leave {
+        client: ClientId,
+    },
     Timer,
+    RateLimited {
+        client: ClientId,
+    },
+
+    /// Wakes the room after the service calls [StateroomContext::close_room].
+    Close,
 }
 
-impl ServerState {
-    pub fn new(factory: impl StateroomServiceFactory) -> Self {
+impl Room {
+    /// Build and initialize a service on the given spawner, and start running it there.
+    ///
+    /// The service is built before this returns, so that errors from the factory
+    /// can be reported to the client that requested the room. If the service closes the
+    /// room, or if `empty_room_timeout` is set and the room has had no clients for that
+    /// long, it is removed from `rooms` and its service is dropped. Otherwise the room
+    /// lives as long as the server.
+    /// `history` is used unless the factory chooses a history limit for the room.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn new<F: StateroomServiceFactory>(
+        factory: Arc<F>,
+        room_id: &str,
+        config: &[u8],
+        spawner: &ServiceSpawner,
+        panic_policy: PanicPolicy,
+        rate_limits: &RateLimits,
+        history: Option<HistoryLimit>,
+        empty_room_timeout: Option<Duration>,
+        rooms: Weak<RoomMap>,
+    ) -> Result<Self, RoomError> {
         let (tx, mut rx) = tokio::sync::mpsc::channel::<Event>(100);
+        let history = factory
+            .history_limit(room_id)
+            .or(history)
+            .filter(|limit| limit.max_messages > 0)
+            .map(|limit| Arc::new(Mutex::new(HistoryBuffer::new(limit))));
 
         let senders = Arc::new(DashMap::new());
+        let groups = Arc::new(DashMap::new());
+        let protocols = Arc::new(DashMap::new());
+        let accepting_clients = Arc::new(Mutex::new(true));
 
-        let senders_ = senders.clone();
-        let tx_ = tx.clone();
-        let handle = tokio::spawn(async move {
-            let context = Arc::new(ServerStateroomContext {
-                senders: senders_.clone(),
-                event_sender: Arc::new(tx_),
-                timer_handle: Mutex::new(None),
-            });
+        let context = Arc::new(ServerStateroomContext {
+            senders: senders.clone(),
+            groups: groups.clone(),
+            protocols: protocols.clone(),
+            event_sender: tx.downgrade(),
+            timer_handle: Mutex::new(None),
+            close_reason: Mutex::new(None),
+            history: history.clone(),
+            accepting_clients: accepting_clients.clone(),
+        });
+
+        let (started_sender, started) = oneshot::channel();
+        let room_id_ = room_id.to_string();
+        let config_ = config.to_vec();
+        spawner
+            .spawn(async move {
+                let room_id = room_id_;
+                // Build the service where it will run, so that slow factories don't
+                // hold up the runtime that accepts connections.
+                let mut service =
+                    match start_service(factory.as_ref(), &room_id, &config_, &context, &[]) {
+                        Ok(service) => {
+                            let _ = started_sender.send(Ok(()));
+                            service
+                        }
+                        Err(error) => {
+                            let _ = started_sender.send(Err(error));
+                            return;
+                        }
+                    };
+
+                let idle_deadline =
+                    || empty_room_timeout.map(|timeout| tokio::time::Instant::now() + timeout);
+                let mut empty_deadline = idle_deadline();
+                loop {
+                    let event = match empty_deadline {
+                        // While the room is empty, only wait so long for a client to join.
+                        Some(deadline) => {
+                            match tokio::time::timeout_at(deadline, rx.recv()).await {
+                                Ok(event) => event,
+                                Err(_) => {
+                                    if close_if_empty(&rooms, &room_id, &context) {
+                                        tracing::info!(room_id, "Closing empty room.");
+                                        break;
+                                    }
+                                    // A client is joining.
+                                    empty_deadline = None;
+                                    continue;
+                                }
+                            }
+                        }
+                        None => rx.recv().await,
+                    };
+                    let Some(event) = event else {
+                        break;
+                    };
 
-            let mut service = factory.build("", context.clone()).unwrap();
-            service.init(context.as_ref());
+                    if matches!(event, Event::Join { .. }) {
+                        empty_deadline = None;
+                    }
+                    let leave = matches!(event, Event::Leave { .. });
+                    let result = catch_unwind(AssertUnwindSafe(|| {
+                        dispatch_event(&mut service, event, context.as_ref())
+                    }));
 
-            loop {
-                let msg = rx.recv().await;
-                match msg {
-                    Some(Event::Message { client, message }) => match message {
-                        Message::Text(msg) => {
-                            service.message(client, MessagePayload::Text(msg), context.as_ref())
+                    match result {
+                        Ok(()) => {
+                            let close_reason = context
+                                .close_reason
+                                .lock()
+                                .expect("close reason lock poisoned")
+                                .take();
+                            if let Some(reason) = close_reason {
+                                // Stop accepting connections before clients are
+                                // disconnected, so that none can join in between.
+                                rx.close();
+                                forget_room(&rooms, &room_id, &context);
+                                shut_down(&mut service, &room_id, &reason, context.as_ref());
+                                break;
+                            }
                         }
-                        Message::Binary(msg) => {
-                            service.message(client, MessagePayload::Bytes(msg), context.as_ref())
+                        Err(panic) => {
+                            tracing::error!(
+                                room_id,
+                                reason = panic_message(&panic),
+                                "Service panicked."
+                            );
+
+                            match panic_policy {
+                                PanicPolicy::Restart => {
+                                    tracing::info!(room_id, "Restarting service.");
+                                    let clients = context.connected_clients();
+                                    match start_service(
+                                        factory.as_ref(),
+                                        &room_id,
+                                        &config_,
+                                        &context,
+                                        &clients,
+                                    ) {
+                                        Ok(new_service) => service = new_service,
+                                        Err(error) => {
+                                            tracing::error!(
+                                                room_id,
+                                                ?error,
+                                                "Could not restart service."
+                                            );
+                                            rx.close();
+                                            forget_room(&rooms, &room_id, &context);
+                                            context.close_all(
+                                                CLOSE_CODE_INTERNAL_ERROR,
+                                                "Room closed.",
+                                            );
+                                            break;
+                                        }
+                                    }
+                                }
+                                PanicPolicy::Close { code } => {
+                                    rx.close();
+                                    forget_room(&rooms, &room_id, &context);
+                                    context.close_all(code, "Room closed.");
+                                    break;
+                                }
+                            }
                         }
-                        Message::Close(_) => {}
-                        msg => tracing::warn!("Ignoring unhandled message: {:?}", msg),
-                    },
-                    Some(Event::Join { client }) => service.connect(client, context.as_ref()),
-                    Some(Event::Leave { client }) => service.disconnect(client, context.as_ref()),
-                    Some(Event::Timer) => {
-                        service.timer(context.as_ref());
                     }
-                    None => break,
+
+                    if leave && context.senders.is_empty() {
+                        empty_deadline = idle_deadline();
+                    }
                 }
-            }
-        });
+            })
+            .map_err(|error| RoomError {
+                kind: BuildErrorKind::Unavailable,
+                reason: format!("Could not spawn service: {}", error),
+            })?;
+        started.await.unwrap_or_else(|_| {
+            Err(RoomError {
+                kind: BuildErrorKind::Internal,
+                reason: "Service stopped while starting.".to_string(),
+            })
+        })?;
 
-        Self {
-            handle,
+        Ok(Self {
+            config: config.to_vec(),
             inbound_sender: tx,
             senders,
+            groups,
+            protocols,
             next_client_id: AtomicU32::new(1),
-        }
+            rate_limiter: Mutex::new(RateLimiter::for_room(rate_limits)),
+            history,
+            accepting_clients,
+        })
     }
 
-    pub fn remove(&self, client: &ClientId) {
-        self.inbound_sender
-            .try_send(Event::Leave { client: *client })
-            .unwrap();
+    /// Whether the room is running and accepting clients.
+    pub fn is_open(&self) -> bool {
+        *self
+            .accepting_clients
+            .lock()
+            .expect("accepting clients lock poisoned")
+            && !self.inbound_sender.is_closed()
+    }
+
+    /// Returns whether the room's aggregate rate limits allow a message of the given
+    /// size, counting it against them if so.
+    pub fn try_accept_message(&self, size: usize) -> bool {
+        self.rate_limiter
+            .lock()
+            .expect("rate limiter lock poisoned")
+            .check(size, || true)
+    }
+
+    /// Unregisters a client from the room, waiting for room in the service's event
+    /// queue if it is full so that the service always learns of the disconnect.
+    pub async fn remove(&self, client: &ClientId) {
+        // Remove the sender first, so that the client is no longer listed in
+        // `connected_clients()` by the time the service handles its disconnect.
         self.senders.remove(client);
+        self.groups.retain(|_, members| {
+            members.remove(client);
+            !members.is_empty()
+        });
+        if self
+            .inbound_sender
+            .send(Event::Leave { client: *client })
+            .await
+            .is_err()
+        {
+            tracing::debug!(?client, "Room stopped before the client left.");
+        }
+    }
+
+    /// Registers a new client with the room, along with the subprotocol negotiated
+    /// for its connection. If the room keeps a history, it is queued for the client
+    /// ahead of any other message.
+    ///
+    /// If the service's event queue is full, this waits for room rather than turning
+    /// the client away. Returns `None` only if the room is no longer accepting clients,
+    /// for example because its service was stopped after a panic, or because the room
+    /// was closed after its last client left.
+    pub async fn connect(
+        &self,
+        protocol: Option<String>,
+    ) -> Option<(Sender<Event>, Receiver<Message>, ClientId)> {
+        let (client_id, rx) = self.add_client(protocol)?;
+
+        if self
+            .inbound_sender
+            .send(Event::Join { client: client_id })
+            .await
+            .is_err()
+        {
+            tracing::warn!(client=?client_id, "Room stopped before the client joined.");
+            self.senders.remove(&client_id);
+            self.protocols.remove(&client_id);
+            return None;
+        }
+        Some((self.inbound_sender.clone(), rx, client_id))
     }
 
-    pub fn connect(&self) -> (Sender<Event>, Receiver<Message>, ClientId) {
+    /// Assigns an ID to a new client and starts delivering messages to it, beginning
+    /// with the room's history.
+    fn add_client(&self, protocol: Option<String>) -> Option<(ClientId, Receiver<Message>)> {
+        // Held until the client is added, so that the room can't be closed for being
+        // empty in between; see [close_if_empty].
+        let accepting_clients = self
+            .accepting_clients
+            .lock()
+            .expect("accepting clients lock poisoned");
+        if !*accepting_clients {
+            return None;
+        }
         let client_id = self.next_client_id();
-        let (tx, rx) = tokio::sync::mpsc::channel::<Message>(100);
 
+        // The client starts receiving broadcasts once its sender is inserted, so the
+        // history is held until then; see [ServerStateroomContext::try_send].
+        let mut history = self
+            .history
+            .as_deref()
+            .map(|history| history.lock().expect("history lock poisoned"));
+        let replay: Vec<Message> = match &mut history {
+            Some(history) => history.messages().cloned().collect(),
+            None => Vec::new(),
+        };
+
+        let (tx, rx) = tokio::sync::mpsc::channel::<Message>(CLIENT_QUEUE_LENGTH + replay.len());
+        for message in replay {
+            log_send_error(&client_id, tx.try_send(message));
+        }
+
+        if let Some(protocol) = protocol {
+            self.protocols.insert(client_id, protocol);
+        }
         self.senders.insert(client_id, tx);
-        self.inbound_sender
-            .try_send(Event::Join { client: client_id })
-            .unwrap();
-        (self.inbound_sender.clone(), rx, client_id)
+        Some((client_id, rx))
     }
 
     fn next_client_id(&self) -> ClientId {
-        let r = self
-            .next_client_id
-            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let r = self.next_client_id.fetch_add(1, Ordering::Relaxed);
         ClientId(r)
     }
 }
+
+/// Stops a room from accepting clients and removes it from `rooms`, unless a client
+/// joined it in the meantime. Returns whether the room was closed.
+fn close_if_empty(rooms: &Weak<RoomMap>, room_id: &str, context: &ServerStateroomContext) -> bool {
+    {
+        let mut accepting_clients = context
+            .accepting_clients
+            .lock()
+            .expect("accepting clients lock poisoned");
+        if !context.senders.is_empty() {
+            return false;
+        }
+        *accepting_clients = false;
+    }
+    forget_room(rooms, room_id, context);
+    true
+}
+
+/// Removes a room from `rooms`, if it hasn't been replaced already.
+fn forget_room(rooms: &Weak<RoomMap>, room_id: &str, context: &ServerStateroomContext) {
+    if let Some(rooms) = rooms.upgrade() {
+        rooms.remove_if(room_id, |_, room| {
+            Arc::ptr_eq(&room.senders, &context.senders)
+        });
+    }
+}
+
+/// Builds and initializes a service, then replays `connect` for the given clients.
+fn start_service<F: StateroomServiceFactory>(
+    factory: &F,
+    room_id: &str,
+    config: &[u8],
+    context: &Arc<ServerStateroomContext>,
+    clients: &[ClientId],
+) -> Result<F::Service, RoomError> {
+    let mut service = factory
+        .build_with_config(room_id, config, context.clone())
+        .map_err(|error| RoomError {
+            kind: factory.error_kind(&error),
+            reason: format!("{:?}", error),
+        })?;
+
+    let result = catch_unwind(AssertUnwindSafe(|| {
+        service.init(context.as_ref());
+        for client in clients {
+            service.connect(*client, context.as_ref());
+        }
+    }));
+
+    match result {
+        Ok(()) => Ok(service),
+        Err(panic) => Err(RoomError {
+            kind: BuildErrorKind::Internal,
+            reason: format!("Service panicked while starting: {}", panic_message(&panic)),
+        }),
+    }
+}
+
+fn dispatch_event(
+    service: &mut impl StateroomService,
+    event: Event,
+    context: &ServerStateroomContext,
+) {
+    match event {
+        Event::Message { client, message } => match message {
+            // With the `bytes` feature, frames are handed to the service without copying;
+            // text is only checked again for UTF-8 validity.
+            #[cfg(feature = "bytes")]
+            Message::Text(msg) => {
+                let text =
+                    ByteString::try_from(Bytes::from(msg)).expect("text message is valid UTF-8");
+                service.message(client, MessagePayload::Text(text), context)
+            }
+            #[cfg(not(feature = "bytes"))]
+            Message::Text(msg) => service.message(client, msg.as_str().into(), context),
+            #[allow(clippy::useless_conversion)]
+            Message::Binary(msg) => {
+                service.message(client, MessagePayload::Bytes(msg.into()), context)
+            }
+            Message::Close(_) => {}
+            msg => tracing::warn!("Ignoring unhandled message: {:?}", msg),
+        },
+        Event::Join { client } => service.connect(client, context),
+        Event::Leave { client } => {
+            service.disconnect(client, context);
+            // Keep the protocol until the service has handled the disconnect, so that
+            // it can still be looked up from `disconnect`.
+            context.protocols.remove(&client);
+            context.remove_from_groups(&client);
+        }
+        Event::Timer => service.timer(context),
+        Event::RateLimited { client } => service.rate_limited(client, context),
+        Event::Close => {}
+    }
+}
+
+/// Runs the service's shutdown hook and disconnects every client, after the service
+/// called [StateroomContext::close_room].
+fn shut_down(
+    service: &mut impl StateroomService,
+    room_id: &str,
+    reason: &str,
+    context: &ServerStateroomContext,
+) {
+    tracing::info!(room_id, reason, "Service closed the room.");
+
+    if let Err(panic) = catch_unwind(AssertUnwindSafe(|| service.shutdown(context))) {
+        tracing::error!(
+            room_id,
+            reason = panic_message(&panic),
+            "Service panicked while shutting down."
+        );
+    }
+
+    context.close_all(CLOSE_CODE_NORMAL, reason);
+}
+
+/// Truncates a close reason to fit in a close frame, at a character boundary.
+fn truncate_reason(reason: &str) -> &str {
+    let mut end = reason.len().min(MAX_CLOSE_REASON_LENGTH);
+    while !reason.is_char_boundary(end) {
+        end -= 1;
+    }
+    &reason[..end]
+}
+
+fn panic_message(panic: &Box<dyn Any + Send>) -> &str {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown panic"
+    }
+}
+
+fn log_send_error<T>(client_id: &ClientId, result: Result<(), TrySendError<T>>) {
+    match result {
+        Ok(()) => {}
+        Err(TrySendError::Full(_)) => {
+            tracing::warn!(?client_id, "Dropping message for client with a full queue.")
+        }
+        // The client is disconnecting; its removal is already on the way to the service.
+        Err(TrySendError::Closed(_)) => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        truncate_reason, Event, PanicPolicy, RoomMode, ServerState, GENERATED_ROOM_ID_LENGTH,
+        MAX_CLOSE_REASON_LENGTH,
+    };
+    use crate::{RateLimits, ServiceExecutor, ServiceSpawner};
+    use axum::extract::ws::{CloseFrame, Message};
+    use stateroom::{
+        BuildErrorKind, ClientId, DefaultStateroomFactory, HistoryLimit, MessagePayload,
+        MessageRecipient, StateroomContext, StateroomService, StateroomServiceFactory,
+    };
+    use std::{
+        convert::Infallible,
+        sync::{mpsc, Arc, Mutex},
+        time::Duration,
+    };
+    use tokio::sync::mpsc::error::TrySendError;
+
+    #[derive(Default)]
+    struct BroadcastService;
+
+    impl StateroomService for BroadcastService {
+        fn message(&mut self, _: ClientId, message: MessagePayload, ctx: &impl StateroomContext) {
+            ctx.send_message(MessageRecipient::Broadcast, message);
+        }
+    }
+
+    /// Replies to every message with the clients the context reports as connected.
+    #[derive(Default)]
+    struct ClientsService;
+
+    impl StateroomService for ClientsService {
+        fn message(&mut self, client: ClientId, _: MessagePayload, ctx: &impl StateroomContext) {
+            let clients: Vec<u32> = ctx.connected_clients().into_iter().map(u32::from).collect();
+            ctx.send_message(client, format!("{:?} {}", clients, ctx.client_count()));
+        }
+    }
+
+    /// Handles messages of the form `<command> <argument> <client ids>`, and replies
+    /// `done` to the sender once a command is handled.
+    #[derive(Default)]
+    struct RoutingService;
+
+    impl StateroomService for RoutingService {
+        fn message(
+            &mut self,
+            client: ClientId,
+            message: MessagePayload,
+            ctx: &impl StateroomContext,
+        ) {
+            let message = message.text().unwrap().to_string();
+            let mut parts = message.split(' ');
+            let command = parts.next().unwrap();
+            let argument = parts.next().unwrap_or_default();
+            let clients: Vec<ClientId> = parts
+                .next()
+                .unwrap_or_default()
+                .split(',')
+                .filter(|id| !id.is_empty())
+                .map(|id| ClientId(id.parse().unwrap()))
+                .collect();
+
+            match command {
+                "join" => {
+                    for member in clients {
+                        ctx.join_group(member, argument);
+                    }
+                }
+                "leave" => {
+                    for member in clients {
+                        ctx.leave_group(member, argument);
+                    }
+                }
+                "group" => {
+                    ctx.send_message(MessageRecipient::Group(argument.to_string()), argument)
+                }
+                "clients" => ctx.send_message(MessageRecipient::Clients(clients), argument),
+                "except" => {
+                    ctx.send_message(MessageRecipient::EveryoneExceptMany(clients), argument)
+                }
+                _ => panic!("unknown command {}", command),
+            }
+            ctx.send_message(client, "done");
+        }
+    }
+
+    /// Reports the thread that each call into the service runs on, and sets a timer
+    /// when a client connects.
+    struct ThreadService {
+        built_on: String,
+        initialized_on: String,
+    }
+
+    fn thread_name() -> String {
+        std::thread::current()
+            .name()
+            .unwrap_or_default()
+            .to_string()
+    }
+
+    impl Default for ThreadService {
+        fn default() -> Self {
+            ThreadService {
+                built_on: thread_name(),
+                initialized_on: String::new(),
+            }
+        }
+    }
+
+    impl StateroomService for ThreadService {
+        fn init(&mut self, _: &impl StateroomContext) {
+            self.initialized_on = thread_name();
+        }
+
+        fn connect(&mut self, client: ClientId, ctx: &impl StateroomContext) {
+            ctx.send_message(client, format!("build {}", self.built_on));
+            ctx.send_message(client, format!("init {}", self.initialized_on));
+            ctx.send_message(client, format!("connect {}", thread_name()));
+            ctx.set_timer(1);
+        }
+
+        fn message(&mut self, client: ClientId, _: MessagePayload, ctx: &impl StateroomContext) {
+            ctx.send_message(client, format!("message {}", thread_name()));
+        }
+
+        fn timer(&mut self, ctx: &impl StateroomContext) {
+            ctx.send_message(
+                MessageRecipient::Broadcast,
+                format!("timer {}", thread_name()),
+            );
+        }
+    }
+
+    /// Greets clients and says goodbye to them, blocks on `wait` (after replying
+    /// `waiting`) until released through the factory's gate, and panics on `panic`.
+    struct GateService {
+        gate: Arc<Mutex<mpsc::Receiver<()>>>,
+    }
+
+    impl StateroomService for GateService {
+        fn connect(&mut self, client: ClientId, ctx: &impl StateroomContext) {
+            ctx.send_message(client, "hello");
+        }
+
+        fn disconnect(&mut self, client: ClientId, ctx: &impl StateroomContext) {
+            ctx.send_message(MessageRecipient::EveryoneExcept(client), "goodbye");
+        }
+
+        fn message(
+            &mut self,
+            client: ClientId,
+            message: MessagePayload,
+            ctx: &impl StateroomContext,
+        ) {
+            match message.text() {
+                Some("wait") => {
+                    ctx.send_message(client, "waiting");
+                    self.gate.lock().unwrap().recv().unwrap()
+                }
+                Some("panic") => panic!("asked to panic"),
+                _ => ctx.send_message(client, message),
+            }
+        }
+    }
+
+    struct GateFactory {
+        gate: Arc<Mutex<mpsc::Receiver<()>>>,
+    }
+
+    impl StateroomServiceFactory for GateFactory {
+        type Service = GateService;
+        type Error = Infallible;
+
+        fn build(
+            &self,
+            _: &str,
+            _: Arc<impl StateroomContext>,
+        ) -> Result<Self::Service, Self::Error> {
+            Ok(GateService {
+                gate: self.gate.clone(),
+            })
+        }
+    }
+
+    /// Builds a server whose rooms block on `wait` until a value is sent on the gate.
+    /// Services run on their own threads, so that a blocked one doesn't hold up the test.
+    fn gated_server_state(panic_policy: PanicPolicy) -> (ServerState, mpsc::Sender<()>) {
+        let (gate_sender, gate) = mpsc::channel();
+        let state = ServerState::new(
+            GateFactory {
+                gate: Arc::new(Mutex::new(gate)),
+            },
+            ServiceSpawner::new(ServiceExecutor::DedicatedThread).unwrap(),
+            panic_policy,
+            RoomMode::AutoCreate,
+            RateLimits::default(),
+            Vec::new(),
+            None,
+            None,
+        );
+        (state, gate_sender)
+    }
+
+    /// Greets each client with the room's configuration.
+    struct ConfigService {
+        config: String,
+    }
+
+    impl StateroomService for ConfigService {
+        fn connect(&mut self, client: ClientId, ctx: &impl StateroomContext) {
+            ctx.send_message(client, self.config.clone());
+        }
+    }
+
+    struct ConfigFactory;
+
+    impl StateroomServiceFactory for ConfigFactory {
+        type Service = ConfigService;
+        type Error = Infallible;
+
+        fn build(
+            &self,
+            room_id: &str,
+            context: Arc<impl StateroomContext>,
+        ) -> Result<Self::Service, Self::Error> {
+            self.build_with_config(room_id, &[], context)
+        }
+
+        fn build_with_config(
+            &self,
+            _: &str,
+            config: &[u8],
+            _: Arc<impl StateroomContext>,
+        ) -> Result<Self::Service, Self::Error> {
+            Ok(ConfigService {
+                config: String::from_utf8(config.to_vec()).unwrap(),
+            })
+        }
+    }
+
+    fn config_server_state(
+        room_mode: RoomMode,
+        empty_room_timeout: Option<Duration>,
+    ) -> ServerState {
+        ServerState::new(
+            ConfigFactory,
+            ServiceSpawner::new(ServiceExecutor::Inline).unwrap(),
+            PanicPolicy::default(),
+            room_mode,
+            RateLimits::default(),
+            Vec::new(),
+            None,
+            empty_room_timeout,
+        )
+    }
+
+    /// Polls a condition until it holds, or panics after about a second.
+    async fn wait_until(condition: impl Fn() -> bool) {
+        for _ in 0..100 {
+            if condition() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("condition did not hold in time");
+    }
+
+    fn text(text: &str) -> Option<Message> {
+        Some(Message::Text(text.into()))
+    }
+
+    fn server_state<S: StateroomService + Default>(history: Option<HistoryLimit>) -> ServerState {
+        server_state_on::<S>(ServiceExecutor::Inline, history)
+    }
+
+    fn server_state_on<S: StateroomService + Default>(
+        executor: ServiceExecutor,
+        history: Option<HistoryLimit>,
+    ) -> ServerState {
+        ServerState::new(
+            DefaultStateroomFactory::<S>::default(),
+            ServiceSpawner::new(executor).unwrap(),
+            PanicPolicy::default(),
+            RoomMode::AutoCreate,
+            RateLimits::default(),
+            Vec::new(),
+            history,
+            None,
+        )
+    }
+
+    /// Checks that each room's service is built, and handles events and timers, on the
+    /// expected thread.
+    fn assert_runs_on(executor: ServiceExecutor, threads: &[&str]) {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .thread_name("server-runtime")
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let state = server_state_on::<ThreadService>(executor, None);
+
+            for (i, thread) in threads.iter().enumerate() {
+                let room = state.room(&format!("room-{}", i)).await.unwrap();
+                let (events, mut messages, client) = room.connect(None).await.unwrap();
+                let expected =
+                    |call: &str| Some(Message::Text(format!("{} {}", call, thread).into()));
+
+                assert_eq!(messages.recv().await, expected("build"));
+                assert_eq!(messages.recv().await, expected("init"));
+                assert_eq!(messages.recv().await, expected("connect"));
+                assert_eq!(messages.recv().await, expected("timer"));
+
+                let message = Message::Text("hello".into());
+                events
+                    .send(Event::Message { client, message })
+                    .await
+                    .unwrap();
+                assert_eq!(messages.recv().await, expected("message"));
+            }
+        });
+    }
+
+    #[test]
+    fn test_inline_executor() {
+        assert_runs_on(ServiceExecutor::Inline, &["server-runtime"]);
+    }
+
+    #[test]
+    fn test_dedicated_thread_executor() {
+        assert_runs_on(
+            ServiceExecutor::DedicatedThread,
+            &["stateroom-service", "stateroom-service"],
+        );
+    }
+
+    #[test]
+    fn test_thread_pool_executor() {
+        assert_runs_on(
+            ServiceExecutor::ThreadPool { threads: 2 },
+            &[
+                "stateroom-service-0",
+                "stateroom-service-1",
+                "stateroom-service-0",
+            ],
+        );
+    }
+
+    #[test]
+    fn test_join_and_leave_wait_for_full_queue() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let (state, gate) = gated_server_state(PanicPolicy::default());
+            let room = state.room("room").await.unwrap();
+            let (events, mut first, client) = room.connect(None).await.unwrap();
+            assert_eq!(first.recv().await, text("hello"));
+            let (_, _, second) = room.connect(None).await.unwrap();
+
+            // Hold the service up and fill its queue behind it.
+            let message = Message::Text("wait".into());
+            events
+                .send(Event::Message { client, message })
+                .await
+                .unwrap();
+            assert_eq!(first.recv().await, text("waiting"));
+            while !matches!(events.try_send(Event::Timer), Err(TrySendError::Full(_))) {}
+
+            let joining = tokio::spawn({
+                let room = room.clone();
+                async move { room.connect(None).await }
+            });
+            let leaving = tokio::spawn({
+                let room = room.clone();
+                async move { room.remove(&second).await }
+            });
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            assert!(!joining.is_finished());
+            assert!(!leaving.is_finished());
+
+            gate.send(()).unwrap();
+            let (_, mut third, _) = joining.await.unwrap().unwrap();
+            leaving.await.unwrap();
+
+            assert_eq!(third.recv().await, text("hello"));
+            assert_eq!(first.recv().await, text("goodbye"));
+        });
+    }
+
+    #[test]
+    fn test_panic_restarts_service() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let (state, _gate) = gated_server_state(PanicPolicy::Restart);
+            let room = state.room("room").await.unwrap();
+            let (events, mut messages, client) = room.connect(None).await.unwrap();
+            assert_eq!(messages.recv().await, text("hello"));
+
+            for message in ["panic", "echo"] {
+                let message = Message::Text(message.into());
+                events
+                    .send(Event::Message { client, message })
+                    .await
+                    .unwrap();
+            }
+
+            // The new service is told about the client, then handles its next message.
+            assert_eq!(messages.recv().await, text("hello"));
+            assert_eq!(messages.recv().await, text("echo"));
+            assert!(!room.inbound_sender.is_closed());
+        });
+    }
+
+    #[test]
+    fn test_panic_closes_room() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let (state, _gate) = gated_server_state(PanicPolicy::Close { code: 4000 });
+            let room = state.room("room").await.unwrap();
+            let (events, mut messages, client) = room.connect(None).await.unwrap();
+            assert_eq!(messages.recv().await, text("hello"));
+
+            let message = Message::Text("panic".into());
+            events
+                .send(Event::Message { client, message })
+                .await
+                .unwrap();
+
+            let close = Message::Close(Some(CloseFrame {
+                code: 4000,
+                reason: "Room closed.".into(),
+            }));
+            assert_eq!(messages.recv().await, Some(close));
+            assert_eq!(messages.recv().await, None);
+            assert!(room.connect(None).await.is_none());
+
+            // The next connection gets a fresh room.
+            let room = state.room("room").await.unwrap();
+            let (_, mut messages, _) = room.connect(None).await.unwrap();
+            assert_eq!(messages.recv().await, text("hello"));
+        });
+    }
+
+    #[test]
+    fn test_explicit_rooms() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let state = config_server_state(RoomMode::Explicit, None);
+            let error = state.room("unknown").await.unwrap_err();
+            assert_eq!(error.kind, BuildErrorKind::NotFound);
+            assert_eq!(state.room_count(), 0);
+
+            let room_id = state.create_room(b"red").await.unwrap();
+            assert_eq!(room_id.len(), GENERATED_ROOM_ID_LENGTH);
+            assert!(room_id.chars().all(|c| c.is_ascii_alphanumeric()));
+            let other_room_id = state.create_room(b"blue").await.unwrap();
+            assert_ne!(room_id, other_room_id);
+            assert_eq!(state.room_count(), 2);
+
+            let room = state.room(&room_id).await.unwrap();
+            assert!(Arc::ptr_eq(&room, &state.room(&room_id).await.unwrap()));
+            let (_, mut messages, client) = room.connect(None).await.unwrap();
+            assert_eq!(messages.recv().await, text("red"));
+
+            let other_room = state.room(&other_room_id).await.unwrap();
+            let (_, mut messages, _) = other_room.connect(None).await.unwrap();
+            assert_eq!(messages.recv().await, text("blue"));
+
+            // A room outlives its clients, so its link can be used again later.
+            room.remove(&client).await;
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            assert_eq!(state.room_count(), 2);
+            let rejoined = state.room(&room_id).await.unwrap();
+            assert!(Arc::ptr_eq(&room, &rejoined));
+            let (_, mut messages, _) = rejoined.connect(None).await.unwrap();
+            assert_eq!(messages.recv().await, text("red"));
+        });
+    }
+
+    #[test]
+    fn test_empty_rooms_close() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let state = config_server_state(RoomMode::AutoCreate, Some(Duration::from_millis(50)));
+
+            let room = state.room("room").await.unwrap();
+            let (_, mut messages, client) = room.connect(None).await.unwrap();
+            assert_eq!(messages.recv().await, text(""));
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            assert_eq!(state.room_count(), 1);
+
+            room.remove(&client).await;
+            wait_until(|| state.room_count() == 0).await;
+            assert!(room.connect(None).await.is_none());
+
+            // Connecting again builds a new room, which closes if nobody joins it.
+            let new_room = state.room("room").await.unwrap();
+            assert!(!Arc::ptr_eq(&room, &new_room));
+            assert_eq!(state.room_count(), 1);
+            wait_until(|| state.room_count() == 0).await;
+            assert!(new_room.connect(None).await.is_none());
+        });
+    }
+
+    #[test]
+    fn test_connected_clients() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let state = server_state::<ClientsService>(None);
+            let room = state.room("room").await.unwrap();
+
+            let (_, _, first) = room.connect(None).await.unwrap();
+            let (events, mut second, client) = room.connect(None).await.unwrap();
+            let (_, _, third) = room.connect(None).await.unwrap();
+            room.remove(&first).await;
+
+            let message = Message::Text("clients".into());
+            events
+                .send(Event::Message { client, message })
+                .await
+                .unwrap();
+            let expected = format!("{:?} 2", [u32::from(client), u32::from(third)]);
+            assert_eq!(second.recv().await, Some(Message::Text(expected.into())));
+        });
+    }
+
+    #[test]
+    fn test_routing() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let state = server_state::<RoutingService>(None);
+            let room = state.room("room").await.unwrap();
+
+            let (events, mut first, sender) = room.connect(None).await.unwrap();
+            let (_, mut second, second_id) = room.connect(None).await.unwrap();
+            let (_, mut third, third_id) = room.connect(None).await.unwrap();
+            let ids = |clients: &[ClientId]| {
+                let ids: Vec<String> = clients.iter().map(|c| u32::from(*c).to_string()).collect();
+                ids.join(",")
+            };
+
+            // Each command, with the messages the first and second clients receive for it.
+            let cases = [
+                (
+                    format!("join red {}", ids(&[sender, third_id])),
+                    vec![],
+                    vec![],
+                ),
+                ("group red".to_string(), vec!["red"], vec![]),
+                (format!("leave red {}", ids(&[sender])), vec![], vec![]),
+                ("group red".to_string(), vec![], vec![]),
+                ("group blue".to_string(), vec![], vec![]),
+                (
+                    format!("clients c {}", ids(&[sender, second_id])),
+                    vec!["c"],
+                    vec!["c"],
+                ),
+                (format!("except e {}", ids(&[second_id])), vec!["e"], vec![]),
+            ];
+
+            for (command, first_expected, second_expected) in cases {
+                let message = Message::Text(command.clone().into());
+                events
+                    .send(Event::Message {
+                        client: sender,
+                        message,
+                    })
+                    .await
+                    .unwrap();
+                for text in first_expected {
+                    assert_eq!(
+                        first.recv().await,
+                        Some(Message::Text(text.into())),
+                        "{}",
+                        command
+                    );
+                }
+                assert_eq!(
+                    first.recv().await,
+                    Some(Message::Text("done".into())),
+                    "{}",
+                    command
+                );
+                for text in second_expected {
+                    assert_eq!(
+                        second.recv().await,
+                        Some(Message::Text(text.into())),
+                        "{}",
+                        command
+                    );
+                }
+            }
+
+            // The third client stayed in the group after the first one left it.
+            for text in ["red", "red", "e"] {
+                assert_eq!(third.recv().await, Some(Message::Text(text.into())));
+            }
+            assert!(second.try_recv().is_err());
+            assert!(third.try_recv().is_err());
+        });
+    }
+
+    #[test]
+    fn test_disconnected_client_cannot_join_group() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let state = server_state::<RoutingService>(None);
+            let room = state.room("room").await.unwrap();
+
+            let (events, mut first, client) = room.connect(None).await.unwrap();
+            let (_, _, second) = room.connect(None).await.unwrap();
+            room.remove(&second).await;
+
+            let command = format!("join red {},{}", u32::from(client), u32::from(second));
+            let message = Message::Text(command.into());
+            events
+                .send(Event::Message { client, message })
+                .await
+                .unwrap();
+            assert_eq!(first.recv().await, Some(Message::Text("done".into())));
+
+            let members = room.groups.get("red").unwrap();
+            assert_eq!(members.iter().copied().collect::<Vec<_>>(), vec![client]);
+        });
+    }
+
+    #[test]
+    fn test_history_replay() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let state = server_state::<BroadcastService>(Some(HistoryLimit::messages(2)));
+            let room = state.room("room").await.unwrap();
+
+            let (events, mut first, client) = room.connect(None).await.unwrap();
+            for text in ["one", "two", "three"] {
+                let message = Message::Text(text.into());
+                events
+                    .send(Event::Message { client, message })
+                    .await
+                    .unwrap();
+                assert_eq!(first.recv().await, Some(Message::Text(text.into())));
+            }
+
+            let (events, mut second, client) = room.connect(None).await.unwrap();
+            assert_eq!(second.recv().await, Some(Message::Text("two".into())));
+            assert_eq!(second.recv().await, Some(Message::Text("three".into())));
+
+            let message = Message::Text("four".into());
+            events
+                .send(Event::Message { client, message })
+                .await
+                .unwrap();
+            assert_eq!(second.recv().await, Some(Message::Text("four".into())));
+        });
+    }
+
+    #[test]
+    fn test_truncate_reason() {
+        assert_eq!(truncate_reason("Match over."), "Match over.");
+
+        let long = "é".repeat(MAX_CLOSE_REASON_LENGTH);
+        let truncated = truncate_reason(&long);
+        assert_eq!(truncated.len(), MAX_CLOSE_REASON_LENGTH - 1);
+        assert!(long.starts_with(truncated));
+    }
+}
@@ -0,0 +1,102 @@
+//! Measures the cost of creating rooms from a WebAssembly module, with and without
+//! wasmtime's pooling allocator.
+//!
+//! `instantiate` times creating and closing a single room. `room_count` times creating
+//! a batch of rooms that stay open, and prints the resident memory each room adds.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use stateroom::{MessagePayload, MessageRecipient, StateroomContext, StateroomServiceFactory};
+use stateroom_wasm_host::{ModuleLoader, PoolingConfig, WasmHostFactory};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+const ROOM_COUNTS: [u32; 2] = [1_000, 10_000];
+
+struct NullContext;
+
+impl StateroomContext for NullContext {
+    fn send_message(&self, _: impl Into<MessageRecipient>, _: impl Into<MessagePayload>) {}
+
+    fn set_timer(&self, _: u32) {}
+}
+
+fn factories() -> [(&'static str, WasmHostFactory); 2] {
+    let module = concat!(env!("CARGO_MANIFEST_DIR"), "/benches/room.wat");
+    let factory = WasmHostFactory::new(module).unwrap();
+    let pooled = ModuleLoader::new()
+        .with_pooling_allocator(PoolingConfig {
+            max_rooms: ROOM_COUNTS[ROOM_COUNTS.len() - 1],
+            ..PoolingConfig::default()
+        })
+        .load(module)
+        .unwrap();
+
+    [("on_demand", factory), ("pooling", pooled)]
+}
+
+/// The resident set size of this process, on Linux.
+fn resident_bytes() -> Option<u64> {
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    Some(pages * 4096)
+}
+
+fn instantiate(c: &mut Criterion) {
+    let context = Arc::new(NullContext);
+    let mut group = c.benchmark_group("instantiate");
+
+    for (name, factory) in factories() {
+        group.bench_function(name, |b| {
+            b.iter(|| factory.build("room", context.clone()).unwrap());
+        });
+    }
+
+    group.finish();
+}
+
+fn room_count(c: &mut Criterion) {
+    let context = Arc::new(NullContext);
+    let mut group = c.benchmark_group("room_count");
+    group.sample_size(10);
+
+    for (name, factory) in factories() {
+        for count in ROOM_COUNTS {
+            let before = resident_bytes();
+            let rooms: Vec<_> = (0..count)
+                .map(|_| factory.build("room", context.clone()).unwrap())
+                .collect();
+            if let (Some(before), Some(after)) = (before, resident_bytes()) {
+                println!(
+                    "room_count/{}/{}: {} KiB resident per room",
+                    name,
+                    count,
+                    after.saturating_sub(before) / 1024 / u64::from(count)
+                );
+            }
+            drop(rooms);
+
+            group.throughput(Throughput::Elements(count.into()));
+            group.bench_with_input(BenchmarkId::new(name, count), &count, |b, &count| {
+                b.iter_custom(|iters| {
+                    let mut elapsed = Duration::ZERO;
+                    for _ in 0..iters {
+                        let start = Instant::now();
+                        let rooms: Vec<_> = (0..count)
+                            .map(|_| factory.build("room", context.clone()).unwrap())
+                            .collect();
+                        elapsed += start.elapsed();
+                        drop(rooms);
+                    }
+                    elapsed
+                });
+            });
+        }
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, instantiate, room_count);
+criterion_main!(benches);
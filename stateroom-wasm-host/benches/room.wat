@@ -0,0 +1,20 @@
+;; A minimal guest with the memory layout of a small Rust module: 17 pages of initial
+;; memory (a 1 MiB stack plus data), and a WASI import, so that instantiating it costs
+;; about as much as instantiating a real service.
+(module
+  (import "wasi_snapshot_preview1" "fd_write"
+    (func $fd_write (param i32 i32 i32 i32) (result i32)))
+
+  (memory (export "memory") 17)
+  (data (i32.const 1048576) "\01\00\00\00")
+  (data (i32.const 1048580) "\00\00\00\00")
+
+  (global (export "STATEROOM_API_VERSION") i32 (i32.const 1048576))
+  (global (export "STATEROOM_API_PROTOCOL") i32 (i32.const 1048580))
+
+  (func (export "stateroom_malloc") (param $size i32) (result i32)
+    (i32.const 1049600))
+
+  (func (export "stateroom_free") (param $ptr i32) (param $size i32))
+
+  (func (export "stateroom_recv") (param $ptr i32) (param $len i32)))
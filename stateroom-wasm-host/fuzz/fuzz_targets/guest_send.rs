@@ -0,0 +1,37 @@
+//! Drives a [WasmHost] with a guest that passes fuzzer-controlled buffers and bounds to
+//! `stateroom_send`. The host must reject bad input with an error rather than panic.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use stateroom::{ClientId, MessagePayload, MessageRecipient, MessageToProcess, StateroomContext};
+use stateroom_wasm_host::WasmHost;
+use std::sync::{Arc, OnceLock};
+use wasmtime::{Engine, Module};
+
+struct NullContext;
+
+impl StateroomContext for NullContext {
+    fn send_message(&self, _: impl Into<MessageRecipient>, _: impl Into<MessagePayload>) {}
+
+    fn set_timer(&self, _: u32) {}
+}
+
+fn guest() -> &'static (Engine, Module) {
+    static GUEST: OnceLock<(Engine, Module)> = OnceLock::new();
+    GUEST.get_or_init(|| {
+        let engine = Engine::default();
+        let module = Module::new(&engine, include_str!("../guest_send.wat")).unwrap();
+        (engine, module)
+    })
+}
+
+fuzz_target!(|data: &[u8]| {
+    let (engine, module) = guest();
+    let mut host = WasmHost::new("fuzz", module, engine, Arc::new(NullContext)).unwrap();
+
+    let _ = host.try_recv(MessageToProcess::Message {
+        sender: ClientId(1),
+        message: MessagePayload::Bytes(data.to_vec()),
+    });
+});
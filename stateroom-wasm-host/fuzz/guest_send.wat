@@ -0,0 +1,31 @@
+;; Forwards the bytes of each binary message it receives to stateroom_send, so that the
+;; fuzzer controls what the host decodes. If the message is at least 8 bytes long, its
+;; first two little-endian u32s are also used as the pointer and length of a second send,
+;; so that the fuzzer controls the bounds as well.
+;;
+;; Messages arrive as the bincode encoding of MessageToProcess::Message with a
+;; MessagePayload::Bytes payload, whose bytes start 20 bytes in.
+(module
+  (import "env" "stateroom_send" (func $send (param i32 i32)))
+
+  (memory (export "memory") 32)
+  (data (i32.const 0) "\01\00\00\00")
+  (data (i32.const 4) "\00\00\00\00")
+
+  (global (export "STATEROOM_API_VERSION") i32 (i32.const 0))
+  (global (export "STATEROOM_API_PROTOCOL") i32 (i32.const 4))
+
+  (func (export "stateroom_malloc") (param $size i32) (result i32)
+    (i32.const 1024))
+
+  (func (export "stateroom_free") (param i32 i32))
+
+  (func (export "stateroom_recv") (param $ptr i32) (param $len i32)
+    (local $data i32)
+    (local.set $data (i32.add (local.get $ptr) (i32.const 20)))
+    (call $send (local.get $data) (i32.sub (local.get $len) (i32.const 20)))
+    (if (i32.ge_u (local.get $len) (i32.const 28))
+      (then
+        (call $send
+          (i32.load (local.get $data))
+          (i32.load (i32.add (local.get $data) (i32.const 4))))))))
@@ -0,0 +1,176 @@
+use crate::guest_output::{CapturedOutput, GuestOutput};
+use anyhow::{Context, Result};
+use stateroom::OutputSource;
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use wasmtime_wasi::{DirPerms, FilePerms, HostMonotonicClock, HostWallClock, WasiCtxBuilder};
+
+/// What a guest can access through WASI.
+///
+/// By default, a guest has no environment variables or filesystem access, and its output
+/// is captured.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capabilities {
+    /// Environment variables visible to the guest.
+    pub env: BTreeMap<String, String>,
+
+    /// Host directories that the guest may read, keyed by the path at which the guest
+    /// sees them.
+    pub read_only_dirs: BTreeMap<String, PathBuf>,
+
+    /// Where the guest's standard output and error are written.
+    pub stdio: GuestStdio,
+
+    /// With [GuestStdio::Capture], the number of bytes per second that each room may
+    /// write to its standard output and error combined. Output over the limit is
+    /// dropped, and the number of dropped bytes is logged. Defaults to 64 KiB.
+    pub output_bytes_per_second: u32,
+
+    /// With [GuestStdio::Capture], the number of lines of output to keep for each room,
+    /// which can be read through [stateroom::StateroomServiceFactory::recent_output].
+    /// Defaults to 100.
+    pub recent_output_lines: usize,
+
+    /// If set, the clocks visible to the guest are rounded down to a multiple of this
+    /// duration, which limits their use for timing attacks.
+    pub clock_resolution: Option<Duration>,
+}
+
+/// The destination of a guest's standard output and error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum GuestStdio {
+    /// Write to the server's standard output and error, interleaved with other rooms.
+    Inherit,
+
+    /// Emit each line of output as a `tracing` event that carries the room ID and module
+    /// name, and keep recent lines for each room.
+    #[default]
+    Capture,
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Capabilities {
+            env: BTreeMap::new(),
+            read_only_dirs: BTreeMap::new(),
+            stdio: GuestStdio::default(),
+            output_bytes_per_second: 64 * 1024,
+            recent_output_lines: 100,
+            clock_resolution: None,
+        }
+    }
+}
+
+impl Capabilities {
+    /// Creates a builder for the WASI context of a room.
+    pub(crate) fn wasi_builder(
+        &self,
+        room_id: &str,
+        output: &GuestOutput,
+    ) -> Result<WasiCtxBuilder> {
+        let mut builder = WasiCtxBuilder::new();
+
+        for (key, value) in &self.env {
+            builder.env(key, value);
+        }
+
+        for (guest_path, host_path) in &self.read_only_dirs {
+            builder
+                .preopened_dir(host_path, guest_path, DirPerms::READ, FilePerms::READ)
+                .with_context(|| format!("Could not open directory {:?}", host_path))?;
+        }
+
+        match self.stdio {
+            GuestStdio::Inherit => {
+                builder.inherit_stdout().inherit_stderr();
+            }
+            GuestStdio::Capture => {
+                let output = output.register(
+                    room_id,
+                    self.output_bytes_per_second,
+                    self.recent_output_lines,
+                );
+                builder
+                    .stdout(CapturedOutput::new(output.clone(), OutputSource::Stdout))
+                    .stderr(CapturedOutput::new(output, OutputSource::Stderr));
+            }
+        }
+
+        if let Some(resolution) = self.clock_resolution {
+            builder
+                .wall_clock(CoarseWallClock { resolution })
+                .monotonic_clock(CoarseMonotonicClock {
+                    resolution,
+                    start: Instant::now(),
+                });
+        }
+
+        Ok(builder)
+    }
+}
+
+/// Rounds `value` down to a multiple of `resolution`.
+fn round_down(value: Duration, resolution: Duration) -> Duration {
+    if resolution.is_zero() {
+        return value;
+    }
+
+    let ticks = value.as_nanos() / resolution.as_nanos();
+    #[allow(clippy::cast_possible_truncation)]
+    Duration::from_nanos((ticks * resolution.as_nanos()) as u64)
+}
+
+struct CoarseWallClock {
+    resolution: Duration,
+}
+
+impl HostWallClock for CoarseWallClock {
+    fn resolution(&self) -> Duration {
+        self.resolution
+    }
+
+    fn now(&self) -> Duration {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        round_down(now, self.resolution)
+    }
+}
+
+struct CoarseMonotonicClock {
+    resolution: Duration,
+    start: Instant,
+}
+
+impl HostMonotonicClock for CoarseMonotonicClock {
+    fn resolution(&self) -> u64 {
+        #[allow(clippy::cast_possible_truncation)]
+        let resolution = self.resolution.as_nanos() as u64;
+        resolution
+    }
+
+    fn now(&self) -> u64 {
+        #[allow(clippy::cast_possible_truncation)]
+        let now = round_down(self.start.elapsed(), self.resolution).as_nanos() as u64;
+        now
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::round_down;
+    use std::time::Duration;
+
+    #[test]
+    fn test_round_down() {
+        let ms = Duration::from_millis;
+
+        assert_eq!(round_down(Duration::from_micros(2_999), ms(1)), ms(2));
+        assert_eq!(round_down(ms(3_000), ms(1_000)), ms(3_000));
+        assert_eq!(round_down(ms(3_999), ms(1_000)), ms(3_000));
+        assert_eq!(round_down(ms(5), Duration::ZERO), ms(5));
+    }
+}
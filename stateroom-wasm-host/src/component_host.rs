@@ -0,0 +1,245 @@
+use crate::{capabilities::Capabilities, guest_output::GuestOutput, host_context::HostContext};
+use anyhow::Result;
+use bindings::{
+    stateroom::service::{context, types},
+    Service,
+};
+use stateroom::{
+    ClientId, MessageFromProcess, MessagePayload, MessageRecipient, MessageToProcess,
+    StateroomContext, StateroomService,
+};
+use std::sync::{Arc, Mutex, PoisonError};
+use wasmtime::{
+    component::{InstancePre, Linker},
+    Engine, Store,
+};
+use wasmtime_wasi::{IoView, ResourceTable, WasiCtx, WasiView};
+
+/// Bindings generated from `wit/stateroom.wit`. They live in their own module because the
+/// generated `stateroom` module would otherwise shadow the `stateroom` crate.
+mod bindings {
+    wasmtime::component::bindgen!({
+        path: "wit",
+        world: "service",
+    });
+}
+
+/// The per-room state of a component's [Store].
+pub(crate) struct ComponentState {
+    /// Behind never-locked mutexes for the same reason as the WASI context of a core
+    /// module's store.
+    wasi: Mutex<WasiCtx>,
+    table: Mutex<ResourceTable>,
+    context: Arc<dyn HostContext>,
+}
+
+impl IoView for ComponentState {
+    fn table(&mut self) -> &mut ResourceTable {
+        self.table.get_mut().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl WasiView for ComponentState {
+    fn ctx(&mut self) -> &mut WasiCtx {
+        self.wasi.get_mut().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl types::Host for ComponentState {}
+
+impl context::Host for ComponentState {
+    fn send_message(&mut self, recipient: types::MessageRecipient, message: types::MessagePayload) {
+        self.context.dispatch(MessageFromProcess::Message {
+            recipient: recipient.into(),
+            message: message.into(),
+        });
+    }
+
+    fn set_timer(&mut self, ms_delay: u32) {
+        self.context
+            .dispatch(MessageFromProcess::SetTimer { ms_delay });
+    }
+
+    fn join_group(&mut self, client: types::ClientId, group: String) {
+        self.context.dispatch(MessageFromProcess::JoinGroup {
+            client: ClientId(client),
+            group,
+        });
+    }
+
+    fn leave_group(&mut self, client: types::ClientId, group: String) {
+        self.context.dispatch(MessageFromProcess::LeaveGroup {
+            client: ClientId(client),
+            group,
+        });
+    }
+
+    fn connected_clients(&mut self) -> Vec<types::ClientId> {
+        self.context
+            .connected_clients()
+            .into_iter()
+            .map(|client| client.0)
+            .collect()
+    }
+
+    fn client_protocol(&mut self, client: types::ClientId) -> Option<String> {
+        self.context.client_protocol(ClientId(client))
+    }
+
+    fn close_room(&mut self, reason: String) {
+        self.context
+            .dispatch(MessageFromProcess::CloseRoom { reason });
+    }
+}
+
+// These conversions copy only if `stateroom` is built with the `bytes` feature.
+
+impl From<types::MessagePayload> for MessagePayload {
+    #[allow(clippy::useless_conversion)]
+    fn from(message: types::MessagePayload) -> Self {
+        match message {
+            types::MessagePayload::Text(text) => MessagePayload::Text(text.into()),
+            types::MessagePayload::Bytes(bytes) => MessagePayload::Bytes(bytes.into()),
+        }
+    }
+}
+
+impl From<MessagePayload> for types::MessagePayload {
+    #[allow(clippy::useless_conversion)]
+    fn from(message: MessagePayload) -> Self {
+        match message {
+            MessagePayload::Text(text) => types::MessagePayload::Text(text.into()),
+            MessagePayload::Bytes(bytes) => types::MessagePayload::Bytes(bytes.into()),
+        }
+    }
+}
+
+impl From<types::MessageRecipient> for MessageRecipient {
+    fn from(recipient: types::MessageRecipient) -> Self {
+        let clients = |clients: Vec<u32>| clients.into_iter().map(ClientId).collect();
+
+        match recipient {
+            types::MessageRecipient::Broadcast => MessageRecipient::Broadcast,
+            types::MessageRecipient::Client(client) => MessageRecipient::Client(ClientId(client)),
+            types::MessageRecipient::EveryoneExcept(client) => {
+                MessageRecipient::EveryoneExcept(ClientId(client))
+            }
+            types::MessageRecipient::Clients(list) => MessageRecipient::Clients(clients(list)),
+            types::MessageRecipient::EveryoneExceptMany(list) => {
+                MessageRecipient::EveryoneExceptMany(clients(list))
+            }
+            types::MessageRecipient::Group(group) => MessageRecipient::Group(group),
+        }
+    }
+}
+
+/// Creates a linker that provides the `stateroom:service/context` imports and WASI
+/// preview 2.
+pub(crate) fn linker(engine: &Engine) -> Result<Linker<ComponentState>> {
+    let mut linker = Linker::new(engine);
+    wasmtime_wasi::add_to_linker_sync(&mut linker)?;
+    Service::add_to_linker(&mut linker, |state: &mut ComponentState| state)?;
+    Ok(linker)
+}
+
+/// Hosts a [stateroom::StateroomService] implemented by a WebAssembly component that
+/// targets the `stateroom:service` world.
+pub struct ComponentHost {
+    store: Store<ComponentState>,
+    service: Service,
+    room_id: String,
+}
+
+impl ComponentHost {
+    pub(crate) fn new(
+        room_id: &str,
+        engine: &Engine,
+        instance_pre: &InstancePre<ComponentState>,
+        capabilities: &Capabilities,
+        output: &GuestOutput,
+        context: Arc<impl StateroomContext>,
+    ) -> Result<Self> {
+        let state = ComponentState {
+            wasi: Mutex::new(capabilities.wasi_builder(room_id, output)?.build()),
+            table: Mutex::new(ResourceTable::new()),
+            context,
+        };
+        let mut store = Store::new(engine, state);
+        let instance = instance_pre.instantiate(&mut store)?;
+        let service = Service::new(&mut store, &instance)?;
+
+        Ok(ComponentHost {
+            store,
+            service,
+            room_id: room_id.to_string(),
+        })
+    }
+
+    /// Delivers a message to the component by calling the corresponding export.
+    ///
+    /// The [StateroomService] methods panic if this fails; calling it directly allows
+    /// errors, such as a trap in the component, to be handled.
+    pub fn try_recv(&mut self, message: MessageToProcess) -> Result<()> {
+        let handler = self.service.stateroom_service_handler();
+        let store = &mut self.store;
+
+        match message {
+            MessageToProcess::Init => handler.call_init(store, &self.room_id),
+            // The component asks for the protocol through `client-protocol` if it needs it.
+            MessageToProcess::Connect { client, .. } => handler.call_connect(store, client.0),
+            MessageToProcess::Disconnect { client } => handler.call_disconnect(store, client.0),
+            MessageToProcess::Message { sender, message } => {
+                handler.call_message(store, sender.0, &message.into())
+            }
+            MessageToProcess::Timer => handler.call_timer(store),
+            MessageToProcess::RateLimited { client } => handler.call_rate_limited(store, client.0),
+            MessageToProcess::Shutdown => handler.call_shutdown(store),
+        }
+    }
+}
+
+impl StateroomService for ComponentHost {
+    fn init(&mut self, _: &impl StateroomContext) {
+        let message = MessageToProcess::Init;
+        self.try_recv(message).unwrap();
+    }
+
+    fn message(&mut self, sender: ClientId, message: MessagePayload, _: &impl StateroomContext) {
+        let message = MessageToProcess::Message { sender, message };
+        self.try_recv(message).unwrap();
+    }
+
+    fn connect(&mut self, client: ClientId, _: &impl StateroomContext) {
+        let message = MessageToProcess::Connect {
+            client,
+            protocol: None,
+        };
+        self.try_recv(message).unwrap();
+    }
+
+    fn disconnect(&mut self, client: ClientId, _: &impl StateroomContext) {
+        let message = MessageToProcess::Disconnect { client };
+        self.try_recv(message).unwrap();
+    }
+
+    fn timer(&mut self, _: &impl StateroomContext) {
+        let message = MessageToProcess::Timer;
+        self.try_recv(message).unwrap();
+    }
+
+    fn rate_limited(&mut self, client: ClientId, _: &impl StateroomContext) {
+        let message = MessageToProcess::RateLimited { client };
+        self.try_recv(message).unwrap();
+    }
+
+    fn shutdown(&mut self, _: &impl StateroomContext) {
+        // The room is closing either way, so a failure here is only logged.
+        if let Err(error) = self.try_recv(MessageToProcess::Shutdown) {
+            tracing::warn!(
+                room_id = self.room_id,
+                ?error,
+                "Component failed to shut down"
+            );
+        }
+    }
+}
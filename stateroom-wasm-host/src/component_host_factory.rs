@@ -0,0 +1,101 @@
+use crate::{
+    capabilities::Capabilities,
+    component_host::{linker, ComponentHost, ComponentState},
+    guest_output::GuestOutput,
+    wasm_host_factory::{engine, module_name},
+};
+use anyhow::Result;
+use sha2::{Digest, Sha256};
+use stateroom::{OutputLine, ServiceInfo, StateroomContext, StateroomServiceFactory};
+use std::{fs, path::Path, sync::Arc};
+use wasmtime::{
+    component::{Component, InstancePre},
+    Engine,
+};
+
+/// Loads a WebAssembly component that targets the `stateroom:service` world, such that a
+/// [ComponentHost] can be created from it.
+///
+/// This struct is cheaply cloneable, so it can be used to create multiple instances
+/// of the same component.
+#[derive(Clone)]
+pub struct ComponentHostFactory {
+    engine: Engine,
+    instance_pre: InstancePre<ComponentState>,
+    component_hash: String,
+    capabilities: Arc<Capabilities>,
+    output: Arc<GuestOutput>,
+}
+
+impl StateroomServiceFactory for ComponentHostFactory {
+    type Service = ComponentHost;
+    type Error = anyhow::Error;
+
+    fn build(
+        &self,
+        room_id: &str,
+        context: Arc<impl StateroomContext>,
+    ) -> Result<Self::Service, Self::Error> {
+        ComponentHost::new(
+            room_id,
+            &self.engine,
+            &self.instance_pre,
+            &self.capabilities,
+            &self.output,
+            context,
+        )
+    }
+
+    fn service_info(&self) -> ServiceInfo {
+        ServiceInfo {
+            module_hash: Some(self.component_hash.clone()),
+            // Components are versioned by the WIT package they target, not by the
+            // `STATEROOM_API_VERSION` of core modules.
+            api_version: None,
+        }
+    }
+
+    fn recent_output(&self, room_id: &str) -> Option<Vec<OutputLine>> {
+        self.output.recent(room_id)
+    }
+}
+
+impl ComponentHostFactory {
+    pub fn new<P>(component_file: P) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let engine = engine(None)?;
+        tracing::info!(component_file=?component_file.as_ref(), "Loading WebAssembly component");
+        let wasm = fs::read(&component_file)?;
+        let component_hash = format!("{:x}", Sha256::digest(&wasm));
+        let output = GuestOutput::new(&module_name(component_file.as_ref()));
+        let component = Component::new(&engine, wasm)?;
+        let instance_pre = linker(&engine)?.instantiate_pre(&component)?;
+
+        Ok(ComponentHostFactory {
+            engine,
+            instance_pre,
+            component_hash,
+            capabilities: Arc::default(),
+            output: Arc::new(output),
+        })
+    }
+
+    /// Set the WASI capabilities, such as environment variables and readable directories,
+    /// that are given to each room's guest. By default, a guest gets none.
+    #[must_use]
+    pub fn with_capabilities(mut self, capabilities: Capabilities) -> Self {
+        self.capabilities = Arc::new(capabilities);
+        self
+    }
+}
+
+/// Whether a binary WebAssembly file contains a component rather than a core module.
+///
+/// Both start with the same magic number, followed by a version and a layer field that
+/// is `0` for core modules and `1` for components.
+#[must_use]
+pub fn is_component(wasm: &[u8]) -> bool {
+    wasm.len() >= 8 && wasm[..4] == *b"\0asm" && wasm[6..8] == [1, 0]
+}
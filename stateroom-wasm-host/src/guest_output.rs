@@ -0,0 +1,363 @@
+use bytes::Bytes;
+use stateroom::{OutputLine, OutputSource};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex, MutexGuard, PoisonError, Weak},
+    time::{Duration, Instant, SystemTime},
+};
+use wasmtime_wasi::{async_trait, OutputStream, Pollable, StdoutStream, StreamResult};
+
+/// Lines longer than this are split, so that a guest that never writes a newline can't
+/// grow the line buffer without bound.
+const MAX_LINE_LENGTH: usize = 4096;
+
+/// Appended to the part of a line that was kept when the rest was over the rate limit.
+const TRUNCATED_SUFFIX: &[u8] = b" [truncated]";
+
+type RoomMap = Mutex<HashMap<String, Weak<RoomOutput>>>;
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// The captured output of the open rooms of one module, by room ID.
+pub(crate) struct GuestOutput {
+    module_name: Arc<str>,
+    rooms: Arc<RoomMap>,
+}
+
+impl GuestOutput {
+    pub(crate) fn new(module_name: &str) -> Self {
+        GuestOutput {
+            module_name: module_name.into(),
+            rooms: Arc::default(),
+        }
+    }
+
+    /// Creates the output of a new room. It replaces that of an earlier room with the same
+    /// ID, such as one that is being rebuilt after a trap.
+    pub(crate) fn register(
+        &self,
+        room_id: &str,
+        bytes_per_second: u32,
+        max_lines: usize,
+    ) -> Arc<RoomOutput> {
+        let output = Arc::new(RoomOutput {
+            room_id: room_id.to_string(),
+            module_name: self.module_name.clone(),
+            rooms: Arc::downgrade(&self.rooms),
+            bytes_per_second,
+            max_lines,
+            state: Mutex::default(),
+        });
+
+        lock(&self.rooms).insert(room_id.to_string(), Arc::downgrade(&output));
+        output
+    }
+
+    /// The most recent lines written by the guest in an open room, oldest first.
+    pub(crate) fn recent(&self, room_id: &str) -> Option<Vec<OutputLine>> {
+        let output = lock(&self.rooms).get(room_id)?.upgrade()?;
+        let recent = lock(&output.state).recent.iter().cloned().collect();
+        Some(recent)
+    }
+}
+
+/// Splits the standard output and error of one room into lines, which are emitted as
+/// `tracing` events and kept in a ring buffer.
+pub(crate) struct RoomOutput {
+    room_id: String,
+    module_name: Arc<str>,
+    rooms: Weak<RoomMap>,
+    bytes_per_second: u32,
+    max_lines: usize,
+    state: Mutex<OutputState>,
+}
+
+#[derive(Default)]
+struct OutputState {
+    stdout: LineBuffer,
+    stderr: LineBuffer,
+    recent: VecDeque<OutputLine>,
+
+    window_start: Option<Instant>,
+    window_bytes: u32,
+    dropped_bytes: u64,
+}
+
+/// The incomplete line of one output stream.
+#[derive(Default)]
+struct LineBuffer {
+    bytes: Vec<u8>,
+    /// Whether the end of a line was dropped by the rate limit, so the stream's output
+    /// is discarded until the next newline.
+    skipping: bool,
+}
+
+impl RoomOutput {
+    fn write(&self, source: OutputSource, mut bytes: &[u8]) {
+        let mut state = lock(&self.state);
+
+        let now = Instant::now();
+        if state
+            .window_start
+            .is_none_or(|start| now - start >= Duration::from_secs(1))
+        {
+            self.report_dropped(&mut state);
+            state.window_start = Some(now);
+            state.window_bytes = 0;
+        }
+
+        if state.buffer(source).skipping {
+            let skipped = match bytes.iter().position(|&b| b == b'\n') {
+                Some(end) => {
+                    state.buffer(source).skipping = false;
+                    end + 1
+                }
+                None => bytes.len(),
+            };
+            state.dropped_bytes += skipped as u64;
+            bytes = &bytes[skipped..];
+        }
+
+        let budget = (self.bytes_per_second - state.window_bytes) as usize;
+        let allowed = bytes.len().min(budget);
+        #[allow(clippy::cast_possible_truncation)]
+        {
+            state.window_bytes += allowed as u32;
+        }
+        state.dropped_bytes += (bytes.len() - allowed) as u64;
+
+        state
+            .buffer(source)
+            .bytes
+            .extend_from_slice(&bytes[..allowed]);
+        while let Some(line) = next_line(&mut state.buffer(source).bytes) {
+            self.emit(&mut state, source, &line);
+        }
+
+        if allowed < bytes.len() {
+            // Emit what was kept of the line now, rather than joining it to the output
+            // of a later write. The rest of the line is discarded, even if it arrives in
+            // a later window.
+            let mut line = std::mem::take(&mut state.buffer(source).bytes);
+            if !line.is_empty() {
+                if bytes[allowed] != b'\n' {
+                    line.extend_from_slice(TRUNCATED_SUFFIX);
+                }
+                self.emit(&mut state, source, &line);
+            }
+            state.buffer(source).skipping = !bytes.ends_with(b"\n");
+        }
+    }
+
+    fn emit(&self, state: &mut OutputState, source: OutputSource, line: &[u8]) {
+        let text = String::from_utf8_lossy(line);
+        let text = text.strip_suffix('\r').unwrap_or(&text);
+
+        tracing::info!(
+            room_id = %self.room_id,
+            module = %self.module_name,
+            stream = ?source,
+            "{}",
+            text
+        );
+
+        if self.max_lines == 0 {
+            return;
+        }
+        if state.recent.len() == self.max_lines {
+            state.recent.pop_front();
+        }
+        state.recent.push_back(OutputLine {
+            source,
+            text: text.to_string(),
+            time: SystemTime::now(),
+        });
+    }
+
+    fn report_dropped(&self, state: &mut OutputState) {
+        if state.dropped_bytes > 0 {
+            tracing::warn!(
+                room_id = %self.room_id,
+                module = %self.module_name,
+                dropped_bytes = state.dropped_bytes,
+                "Dropped guest output over the rate limit"
+            );
+            state.dropped_bytes = 0;
+        }
+    }
+}
+
+impl Drop for RoomOutput {
+    fn drop(&mut self) {
+        let mut state = lock(&self.state);
+        for source in [OutputSource::Stdout, OutputSource::Stderr] {
+            let line = std::mem::take(&mut state.buffer(source).bytes);
+            if !line.is_empty() {
+                self.emit(&mut state, source, &line);
+            }
+        }
+        self.report_dropped(&mut state);
+
+        // Only remove the entry if it hasn't been replaced by a newer room with this ID.
+        if let Some(rooms) = self.rooms.upgrade() {
+            let mut rooms = lock(&rooms);
+            if rooms
+                .get(&self.room_id)
+                .is_some_and(|room| std::ptr::eq(room.as_ptr(), self))
+            {
+                rooms.remove(&self.room_id);
+            }
+        }
+    }
+}
+
+impl OutputState {
+    fn buffer(&mut self, source: OutputSource) -> &mut LineBuffer {
+        match source {
+            OutputSource::Stdout => &mut self.stdout,
+            OutputSource::Stderr => &mut self.stderr,
+        }
+    }
+}
+
+/// Removes the first complete line from `buffer`, without its newline. Lines of
+/// [MAX_LINE_LENGTH] bytes are complete even without one.
+fn next_line(buffer: &mut Vec<u8>) -> Option<Vec<u8>> {
+    let search = &buffer[..buffer.len().min(MAX_LINE_LENGTH)];
+
+    if let Some(end) = search.iter().position(|&b| b == b'\n') {
+        let mut line: Vec<u8> = buffer.drain(..=end).collect();
+        line.pop();
+        Some(line)
+    } else if buffer.len() >= MAX_LINE_LENGTH {
+        Some(buffer.drain(..MAX_LINE_LENGTH).collect())
+    } else {
+        None
+    }
+}
+
+/// One of a room's output streams, as given to the guest's WASI context.
+#[derive(Clone)]
+pub(crate) struct CapturedOutput {
+    output: Arc<RoomOutput>,
+    source: OutputSource,
+}
+
+impl CapturedOutput {
+    pub(crate) fn new(output: Arc<RoomOutput>, source: OutputSource) -> Self {
+        CapturedOutput { output, source }
+    }
+}
+
+impl StdoutStream for CapturedOutput {
+    fn stream(&self) -> Box<dyn OutputStream> {
+        Box::new(self.clone())
+    }
+
+    fn isatty(&self) -> bool {
+        false
+    }
+}
+
+#[async_trait]
+impl Pollable for CapturedOutput {
+    async fn ready(&mut self) {}
+}
+
+impl OutputStream for CapturedOutput {
+    fn write(&mut self, bytes: Bytes) -> StreamResult<()> {
+        self.output.write(self.source, &bytes);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> StreamResult<()> {
+        Ok(())
+    }
+
+    fn check_write(&mut self) -> StreamResult<usize> {
+        Ok(usize::MAX)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{lock, GuestOutput, MAX_LINE_LENGTH};
+    use stateroom::OutputSource;
+
+    fn recent_text(output: &GuestOutput, room_id: &str) -> Vec<String> {
+        output
+            .recent(room_id)
+            .unwrap()
+            .into_iter()
+            .map(|line| line.text)
+            .collect()
+    }
+
+    #[test]
+    fn test_line_buffering() {
+        let output = GuestOutput::new("module");
+        let room = output.register("room", u32::MAX, 3);
+
+        room.write(OutputSource::Stdout, b"hel");
+        room.write(OutputSource::Stderr, b"oops\r\n");
+        room.write(OutputSource::Stdout, b"lo\nwor");
+        assert_eq!(recent_text(&output, "room"), vec!["oops", "hello"]);
+
+        room.write(OutputSource::Stdout, &vec![b'x'; MAX_LINE_LENGTH + 1]);
+        room.write(OutputSource::Stdout, b"\n");
+        let recent = recent_text(&output, "room");
+        assert_eq!(recent.len(), 3);
+        assert_eq!(recent[1], format!("wor{}", "x".repeat(MAX_LINE_LENGTH - 3)));
+        assert_eq!(recent[2], "xxxx");
+
+        drop(room);
+        assert!(output.recent("room").is_none());
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let output = GuestOutput::new("module");
+        let room = output.register("room", 8, 10);
+
+        room.write(OutputSource::Stdout, b"abc\ndefghijk\n");
+        room.write(OutputSource::Stdout, b"lmn\n");
+
+        assert_eq!(
+            recent_text(&output, "room"),
+            vec!["abc", "defg [truncated]"]
+        );
+    }
+
+    #[test]
+    fn test_rate_limit_mid_line() {
+        let output = GuestOutput::new("module");
+        let room = output.register("room", 8, 10);
+
+        room.write(OutputSource::Stdout, b"abc");
+        room.write(OutputSource::Stdout, b"defghijk");
+        assert_eq!(recent_text(&output, "room"), vec!["abcdefgh [truncated]"]);
+
+        // In the next window, the rest of the cut-off line is still discarded.
+        lock(&room.state).window_start = None;
+        room.write(OutputSource::Stdout, b"lm");
+        room.write(OutputSource::Stdout, b"n\nop\n");
+        assert_eq!(
+            recent_text(&output, "room"),
+            vec!["abcdefgh [truncated]", "op"]
+        );
+    }
+
+    #[test]
+    fn test_replaced_room() {
+        let output = GuestOutput::new("module");
+        let old_room = output.register("room", u32::MAX, 10);
+        let new_room = output.register("room", u32::MAX, 10);
+
+        new_room.write(OutputSource::Stdout, b"new\n");
+        drop(old_room);
+
+        assert_eq!(recent_text(&output, "room"), vec!["new"]);
+    }
+}
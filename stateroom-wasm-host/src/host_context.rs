@@ -0,0 +1,25 @@
+use stateroom::{ClientId, MessageFromProcess, StateroomContext};
+
+/// An object-safe view of a [StateroomContext], so that one linker can be shared by rooms
+/// with different context types.
+pub(crate) trait HostContext: Send + Sync {
+    fn dispatch(&self, message: MessageFromProcess);
+
+    fn connected_clients(&self) -> Vec<ClientId>;
+
+    fn client_protocol(&self, client: ClientId) -> Option<String>;
+}
+
+impl<T: StateroomContext> HostContext for T {
+    fn dispatch(&self, message: MessageFromProcess) {
+        message.dispatch(self);
+    }
+
+    fn connected_clients(&self) -> Vec<ClientId> {
+        StateroomContext::connected_clients(self)
+    }
+
+    fn client_protocol(&self, client: ClientId) -> Option<String> {
+        StateroomContext::client_protocol(self, client)
+    }
+}
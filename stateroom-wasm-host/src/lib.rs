@@ -2,23 +2,36 @@
 //! WebAssembly module. It is the counterpart to `stateroom-wasm`, which is used to
 //! implement a compatible guest module.
 
+pub use capabilities::{Capabilities, GuestStdio};
+pub use component_host::ComponentHost;
+pub use component_host_factory::{is_component, ComponentHostFactory};
+pub use protocol::WireProtocol;
 use std::{
     error::Error,
     fmt::{Debug, Display},
 };
-pub use wasm_host::WasmHost;
-pub use wasm_host_factory::WasmHostFactory;
+pub use wasm_host::{WasmHost, MAX_MESSAGE_SIZE};
+pub use wasm_host_factory::{ModuleLoader, PoolingConfig, WasmHostFactory, PRECOMPILED_EXTENSION};
 
+mod capabilities;
+mod component_host;
+mod component_host_factory;
+mod guest_output;
+mod host_context;
+mod protocol;
 mod wasm_host;
 mod wasm_host_factory;
 
 /// An error encountered while running WebAssembly.
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq)]
 pub enum WasmRuntimeError {
     CouldNotImportMemory,
     CouldNotImportGlobal,
     InvalidApiVersion,
     InvalidProtocolVersion,
+    OutOfBoundsMemoryAccess,
+    InvalidMessage,
+    MessageTooLarge,
 }
 
 impl Display for WasmRuntimeError {
@@ -38,6 +51,13 @@ impl Error for WasmRuntimeError {
             Self::InvalidProtocolVersion => {
                 "WebAssembly module has an incompatible Stateroom protocol version."
             }
+            Self::OutOfBoundsMemoryAccess => {
+                "WebAssembly module passed a pointer outside of its memory."
+            }
+            Self::InvalidMessage => "WebAssembly module sent a message that could not be decoded.",
+            Self::MessageTooLarge => {
+                "WebAssembly module sent a message that exceeds the size limit."
+            }
         }
     }
 }
@@ -0,0 +1,199 @@
+use crate::WasmRuntimeError;
+use anyhow::Result;
+use bincode::Options;
+use serde::Serialize;
+use stateroom::{ClientId, MessageFromProcess, MessagePayload, MessageToProcess};
+
+/// The encoding of messages passed through `stateroom_recv` and `stateroom_send`, as
+/// selected by the module's `STATEROOM_API_PROTOCOL` global.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WireProtocol {
+    /// Protocol `0`: `bincode`'s encoding of the message enums, as produced by
+    /// `stateroom-wasm`.
+    Bincode,
+
+    /// Protocol `1`: UTF-8 JSON, documented in this crate's README, for guests that are
+    /// not written in Rust.
+    Json,
+}
+
+impl WireProtocol {
+    pub fn from_version(version: i32) -> Result<Self, WasmRuntimeError> {
+        match version {
+            0 => Ok(WireProtocol::Bincode),
+            1 => Ok(WireProtocol::Json),
+            _ => Err(WasmRuntimeError::InvalidProtocolVersion),
+        }
+    }
+
+    pub fn encode(self, message: &MessageToProcess) -> Result<Vec<u8>> {
+        self.encode_value(message)
+    }
+
+    /// Encodes a message for a module built against API version 1, or returns `None` if
+    /// the message didn't exist in that version and should not be delivered.
+    pub fn encode_v1(self, message: &MessageToProcess) -> Result<Option<Vec<u8>>> {
+        let message = match message {
+            MessageToProcess::Init => MessageToProcessV1::Init,
+            MessageToProcess::Connect { client, .. } => {
+                MessageToProcessV1::Connect { client: *client }
+            }
+            MessageToProcess::Disconnect { client } => {
+                MessageToProcessV1::Disconnect { client: *client }
+            }
+            MessageToProcess::Message { sender, message } => MessageToProcessV1::Message {
+                sender: *sender,
+                message,
+            },
+            MessageToProcess::Timer => MessageToProcessV1::Timer,
+            MessageToProcess::RateLimited { .. } | MessageToProcess::Shutdown => return Ok(None),
+        };
+        self.encode_value(&message).map(Some)
+    }
+
+    fn encode_value(self, message: &impl Serialize) -> Result<Vec<u8>> {
+        match self {
+            WireProtocol::Bincode => Ok(bincode::serialize(message)?),
+            WireProtocol::Json => Ok(serde_json::to_vec(message)?),
+        }
+    }
+
+    pub fn decode(self, message: &[u8]) -> Result<MessageFromProcess> {
+        match self {
+            // Matches the configuration of `bincode::deserialize`, but bounds allocations by
+            // the size of the message so that length prefixes can't exhaust host memory.
+            WireProtocol::Bincode => Ok(bincode::DefaultOptions::new()
+                .with_fixint_encoding()
+                .allow_trailing_bytes()
+                .with_limit(message.len() as u64)
+                .deserialize(message)?),
+            WireProtocol::Json => Ok(serde_json::from_slice(message)?),
+        }
+    }
+}
+
+/// [MessageToProcess] as it was in API version 1, before clients had subprotocols and
+/// before rate limit and shutdown notifications. Variants must stay in the same order.
+#[derive(Serialize)]
+enum MessageToProcessV1<'a> {
+    Init,
+    Connect {
+        client: ClientId,
+    },
+    Disconnect {
+        client: ClientId,
+    },
+    Message {
+        sender: ClientId,
+        message: &'a MessagePayload,
+    },
+    Timer,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::WireProtocol;
+    use stateroom::{ClientId, MessageFromProcess, MessageRecipient, MessageToProcess};
+
+    /// The JSON encoding is a stable interface for guests, so these examples must match
+    /// the README.
+    #[test]
+    fn test_json_protocol() {
+        let encode =
+            |message| String::from_utf8(WireProtocol::Json.encode(&message).unwrap()).unwrap();
+
+        assert_eq!(encode(MessageToProcess::Init), r#""Init""#);
+        assert_eq!(
+            encode(MessageToProcess::Connect {
+                client: ClientId(1),
+                protocol: Some("chat.v1".to_string()),
+            }),
+            r#"{"Connect":{"client":1,"protocol":"chat.v1"}}"#
+        );
+        assert_eq!(
+            encode(MessageToProcess::Message {
+                sender: ClientId(1),
+                message: "hello".into(),
+            }),
+            r#"{"Message":{"sender":1,"message":{"Text":"hello"}}}"#
+        );
+        assert_eq!(
+            encode(MessageToProcess::Message {
+                sender: ClientId(1),
+                message: vec![1, 2].into(),
+            }),
+            r#"{"Message":{"sender":1,"message":{"Bytes":[1,2]}}}"#
+        );
+
+        let decode = |message: &str| WireProtocol::Json.decode(message.as_bytes()).unwrap();
+
+        let MessageFromProcess::Message { recipient, message } =
+            decode(r#"{"Message":{"recipient":"Broadcast","message":{"Text":"hi"}}}"#)
+        else {
+            panic!("Expected a message.");
+        };
+        assert_eq!(recipient, MessageRecipient::Broadcast);
+        assert_eq!(message.text(), Some("hi"));
+
+        let MessageFromProcess::Message { recipient, .. } =
+            decode(r#"{"Message":{"recipient":{"Clients":[1,2]},"message":{"Bytes":[]}}}"#)
+        else {
+            panic!("Expected a message.");
+        };
+        assert_eq!(
+            recipient,
+            MessageRecipient::Clients(vec![ClientId(1), ClientId(2)])
+        );
+
+        assert!(matches!(
+            decode(r#"{"SetTimer":{"ms_delay":500}}"#),
+            MessageFromProcess::SetTimer { ms_delay: 500 }
+        ));
+    }
+
+    #[test]
+    fn test_v1_encoding() {
+        let connect = MessageToProcess::Connect {
+            client: ClientId(1),
+            protocol: Some("chat.v1".to_string()),
+        };
+        let encode = |protocol: WireProtocol, message| protocol.encode_v1(&message).unwrap();
+
+        assert_eq!(
+            encode(WireProtocol::Json, connect).unwrap(),
+            br#"{"Connect":{"client":1}}"#
+        );
+        assert_eq!(
+            encode(
+                WireProtocol::Bincode,
+                MessageToProcess::Connect {
+                    client: ClientId(1),
+                    protocol: None,
+                }
+            )
+            .unwrap(),
+            [1, 0, 0, 0, 1, 0, 0, 0]
+        );
+
+        // Messages that are unchanged since version 1 are encoded the same way.
+        let message = || MessageToProcess::Message {
+            sender: ClientId(1),
+            message: "hello".into(),
+        };
+        for protocol in [WireProtocol::Bincode, WireProtocol::Json] {
+            assert_eq!(
+                encode(protocol, message()).unwrap(),
+                protocol.encode(&message()).unwrap()
+            );
+        }
+
+        assert!(encode(WireProtocol::Json, MessageToProcess::Shutdown).is_none());
+        assert!(encode(
+            WireProtocol::Json,
+            MessageToProcess::RateLimited {
+                client: ClientId(1)
+            }
+        )
+        .is_none());
+    }
+}
@@ -1,34 +1,64 @@
-use crate::WasmRuntimeError;
+use crate::{
+    capabilities::Capabilities, guest_output::GuestOutput, host_context::HostContext,
+    protocol::WireProtocol, WasmRuntimeError,
+};
 use anyhow::{Context, Result};
 use byteorder::{LittleEndian, ReadBytesExt};
-use stateroom::{
-    ClientId, MessageFromProcess, MessagePayload, MessageToProcess, StateroomContext,
-    StateroomService,
+use stateroom::{ClientId, MessagePayload, MessageToProcess, StateroomContext, StateroomService};
+use std::{
+    borrow::BorrowMut,
+    sync::{Arc, Mutex, PoisonError},
+};
+use wasmtime::{
+    Caller, Engine, Extern, Instance, InstancePre, Linker, Memory, Module, Store, TypedFunc, Val,
 };
-use std::{borrow::BorrowMut, sync::Arc};
-use wasi_common::{sync::WasiCtxBuilder, WasiCtx};
-use wasmtime::{Caller, Engine, Extern, Instance, Linker, Memory, Module, Store, TypedFunc, Val};
+use wasmtime_wasi::preview1::{self, WasiP1Ctx};
 
 const ENV: &str = "env";
 const EXT_MEMORY: &str = "memory";
 const EXT_FN_SEND: &str = "stateroom_send";
+const EXT_FN_CONNECTED_CLIENTS: &str = "stateroom_connected_clients";
 const EXT_FN_RECV: &str = "stateroom_recv";
 const EXT_FN_MALLOC: &str = "stateroom_malloc";
 const EXT_FN_FREE: &str = "stateroom_free";
 const EXT_STATEROOM_VERSION: &str = "STATEROOM_API_VERSION";
 const EXT_STATEROOM_PROTOCOL: &str = "STATEROOM_API_PROTOCOL";
 
-const EXPECTED_API_VERSION: i32 = 1;
-const EXPECTED_PROTOCOL_VERSION: i32 = 0;
+/// The API version that the host implements. Version 2 added the subprotocol to
+/// `Connect`, and the `RateLimited` and `Shutdown` messages.
+pub(crate) const EXPECTED_API_VERSION: i32 = 2;
+
+/// The oldest API version that the host still accepts. Messages are translated to
+/// this version's encoding for modules that export it.
+const LEGACY_API_VERSION: i32 = 1;
+
+/// The largest message that a module may pass to `stateroom_send`.
+pub const MAX_MESSAGE_SIZE: u32 = 16 * 1024 * 1024;
+
+/// The per-room state of a [Store]. Host functions find the room's context here rather
+/// than capturing it, which is what allows them to be linked once per module.
+pub(crate) struct HostState {
+    /// `StateroomService` must be `Sync`, which the WASI context is not. The store is only
+    /// used through `&mut`, so the mutex is never locked; see [Mutex::get_mut].
+    wasi: Mutex<WasiP1Ctx>,
+    context: Arc<dyn HostContext>,
+
+    /// The protocol is exported by the module, so it is only known after instantiation,
+    /// but before the host calls into the module.
+    protocol: Option<WireProtocol>,
+}
 
 /// Hosts a [stateroom::StateroomService] implemented by a WebAssembly module.
 pub struct WasmHost {
-    store: Store<WasiCtx>,
+    store: Store<HostState>,
     memory: Memory,
 
     fn_malloc: TypedFunc<u32, u32>,
     fn_free: TypedFunc<(u32, u32), ()>,
     fn_recv: TypedFunc<(u32, u32), ()>,
+
+    protocol: WireProtocol,
+    api_version: i32,
 }
 
 impl WasmHost {
@@ -37,13 +67,26 @@ impl WasmHost {
         let len = data.len() as u32;
         let pt = self.fn_malloc.call(&mut self.store, len)?;
 
-        self.memory.write(&mut self.store, pt as usize, data)?;
+        self.memory
+            .write(&mut self.store, pt as usize, data)
+            .map_err(|_| WasmRuntimeError::OutOfBoundsMemoryAccess)?;
 
         Ok((pt, len))
     }
 
-    fn try_recv(&mut self, message: MessageToProcess) -> Result<()> {
-        let payload = bincode::serialize(&message).unwrap();
+    /// Delivers a message to the module.
+    ///
+    /// The [StateroomService] methods panic if this fails; calling it directly allows
+    /// errors, such as a [WasmRuntimeError] caused by a misbehaving module, to be handled.
+    pub fn try_recv(&mut self, message: MessageToProcess) -> Result<()> {
+        let payload = if self.api_version == LEGACY_API_VERSION {
+            match self.protocol.encode_v1(&message)? {
+                Some(payload) => payload,
+                None => return Ok(()),
+            }
+        } else {
+            self.protocol.encode(&message)?
+        };
         let (pt, len) = self.put_data(&payload)?;
 
         self.fn_recv.call(&mut self.store, (pt, len))?;
@@ -64,17 +107,16 @@ impl StateroomService for WasmHost {
         self.try_recv(message).unwrap();
     }
 
-    fn connect(&mut self, client: ClientId, _: &impl StateroomContext) {
+    fn connect(&mut self, client: ClientId, context: &impl StateroomContext) {
         let message = MessageToProcess::Connect {
-            client: client.into(),
+            client,
+            protocol: context.client_protocol(client),
         };
         self.try_recv(message).unwrap();
     }
 
     fn disconnect(&mut self, client: ClientId, _: &impl StateroomContext) {
-        let message = MessageToProcess::Disconnect {
-            client: client.into(),
-        };
+        let message = MessageToProcess::Disconnect { client };
         self.try_recv(message).unwrap();
     }
 
@@ -82,13 +124,25 @@ impl StateroomService for WasmHost {
         let message = MessageToProcess::Timer;
         self.try_recv(message).unwrap();
     }
+
+    fn rate_limited(&mut self, client: ClientId, _: &impl StateroomContext) {
+        let message = MessageToProcess::RateLimited { client };
+        self.try_recv(message).unwrap();
+    }
+
+    fn shutdown(&mut self, _: &impl StateroomContext) {
+        // The room is closing either way, so a module that fails here is only logged.
+        if let Err(error) = self.try_recv(MessageToProcess::Shutdown) {
+            tracing::warn!(?error, "Module failed to shut down");
+        }
+    }
 }
 
 #[inline]
-fn get_memory<T>(caller: &mut Caller<'_, T>) -> Memory {
+fn get_memory<T>(caller: &mut Caller<'_, T>) -> Result<Memory, WasmRuntimeError> {
     match caller.get_export(EXT_MEMORY) {
-        Some(Extern::Memory(mem)) => mem,
-        _ => panic!(),
+        Some(Extern::Memory(mem)) => Ok(mem),
+        _ => Err(WasmRuntimeError::CouldNotImportMemory),
     }
 }
 
@@ -98,14 +152,16 @@ fn get_u8_vec<'a, T>(
     memory: &'a Memory,
     start: u32,
     len: u32,
-) -> &'a [u8] {
-    let data = memory
+) -> Result<&'a [u8], WasmRuntimeError> {
+    let start = start as usize;
+    let end = start
+        .checked_add(len as usize)
+        .ok_or(WasmRuntimeError::OutOfBoundsMemoryAccess)?;
+
+    memory
         .data(caller)
-        .get(start as usize..(start + len) as usize);
-    match data {
-        Some(data) => data,
-        None => panic!(),
-    }
+        .get(start..end)
+        .ok_or(WasmRuntimeError::OutOfBoundsMemoryAccess)
 }
 
 pub fn get_global<T>(
@@ -135,6 +191,63 @@ pub fn get_global<T>(
     Ok(result)
 }
 
+/// Creates a linker that provides the Stateroom and WASI imports. It doesn't depend on
+/// the room, so a module only needs to be linked once.
+pub(crate) fn linker(engine: &Engine) -> Result<Linker<HostState>> {
+    let mut linker = Linker::new(engine);
+    preview1::add_to_linker_sync(&mut linker, |state: &mut HostState| {
+        state.wasi.get_mut().unwrap_or_else(PoisonError::into_inner)
+    })?;
+
+    linker.func_wrap(
+        ENV,
+        EXT_FN_SEND,
+        |mut caller: Caller<'_, HostState>, start: u32, len: u32| {
+            if len > MAX_MESSAGE_SIZE {
+                return Err(WasmRuntimeError::MessageTooLarge.into());
+            }
+
+            let memory = get_memory(&mut caller)?;
+            let message = get_u8_vec(&caller, &memory, start, len)?;
+            let message = caller
+                .data()
+                .protocol
+                .ok_or(WasmRuntimeError::InvalidProtocolVersion)?
+                .decode(message)
+                .map_err(|_| WasmRuntimeError::InvalidMessage)?;
+
+            caller.data().context.dispatch(message);
+
+            Ok(())
+        },
+    )?;
+
+    linker.func_wrap(
+        ENV,
+        EXT_FN_CONNECTED_CLIENTS,
+        |mut caller: Caller<'_, HostState>, start: u32, len: u32| {
+            let clients = caller.data().context.connected_clients();
+            let written = clients.len().min(len as usize);
+
+            if written > 0 {
+                let memory = get_memory(&mut caller)?;
+                let data: Vec<u8> = clients[..written]
+                    .iter()
+                    .flat_map(|c| c.0.to_le_bytes())
+                    .collect();
+                memory
+                    .write(&mut caller, start as usize, &data)
+                    .map_err(|_| WasmRuntimeError::OutOfBoundsMemoryAccess)?;
+            }
+
+            #[allow(clippy::cast_possible_truncation)]
+            Ok(clients.len() as u32)
+        },
+    )?;
+
+    Ok(linker)
+}
+
 impl WasmHost {
     pub fn new(
         room_id: &str,
@@ -142,38 +255,34 @@ impl WasmHost {
         engine: &Engine,
         context: Arc<impl StateroomContext>,
     ) -> Result<Self> {
-        let wasi = WasiCtxBuilder::new().inherit_stdio().build();
-
-        let mut store = Store::new(engine, wasi);
-        let mut linker = Linker::new(engine);
-        wasi_common::sync::add_to_linker(&mut linker, |s| s)?;
+        let instance_pre = linker(engine)?.instantiate_pre(module)?;
+        let output = GuestOutput::new(module.name().unwrap_or("module"));
+        Self::from_instance_pre(
+            room_id,
+            &instance_pre,
+            &Capabilities::default(),
+            &output,
+            context,
+        )
+    }
 
-        {
-            #[allow(clippy::redundant_clone)]
-            let context = context.clone();
-            linker.func_wrap(
-                ENV,
-                EXT_FN_SEND,
-                move |mut caller: Caller<'_, WasiCtx>, start: u32, len: u32| {
-                    let memory = get_memory(&mut caller);
-                    let message = get_u8_vec(&caller, &memory, start, len);
-                    let message: MessageFromProcess = bincode::deserialize(&message).unwrap();
-
-                    match message {
-                        MessageFromProcess::Message { recipient, message } => {
-                            context.send_message(recipient, message);
-                        }
-                        MessageFromProcess::SetTimer { ms_delay } => {
-                            context.set_timer(ms_delay);
-                        }
-                    };
-
-                    Ok(())
-                },
-            )?;
-        }
+    /// Instantiates a module that has already been linked, which skips the per-room cost
+    /// of creating a [Linker] and resolving imports.
+    pub(crate) fn from_instance_pre(
+        room_id: &str,
+        instance_pre: &InstancePre<HostState>,
+        capabilities: &Capabilities,
+        output: &GuestOutput,
+        context: Arc<impl StateroomContext>,
+    ) -> Result<Self> {
+        let state = HostState {
+            wasi: Mutex::new(capabilities.wasi_builder(room_id, output)?.build_p1()),
+            context,
+            protocol: None,
+        };
+        let mut store = Store::new(instance_pre.module().engine(), state);
 
-        let instance = linker.instantiate(&mut store, module)?;
+        let instance = instance_pre.instantiate(&mut store)?;
 
         let fn_malloc = instance.get_typed_func::<u32, u32>(&mut store, EXT_FN_MALLOC)?;
 
@@ -185,37 +294,39 @@ impl WasmHost {
             .get_memory(&mut store, EXT_MEMORY)
             .ok_or(WasmRuntimeError::CouldNotImportMemory)?;
 
+        let api_version = get_global(&mut store, &mut memory, &instance, EXT_STATEROOM_VERSION)
+            .context("Stateroom version")?;
+        if api_version != EXPECTED_API_VERSION && api_version != LEGACY_API_VERSION {
+            return Err(WasmRuntimeError::InvalidApiVersion.into());
+        }
+
+        let protocol_version =
+            get_global(&mut store, &mut memory, &instance, EXT_STATEROOM_PROTOCOL)
+                .context("Stateroom protocol")?;
+        let wire_protocol = WireProtocol::from_version(protocol_version)?;
+        store.data_mut().protocol = Some(wire_protocol);
+
         {
             let room_id = room_id.as_bytes();
             #[allow(clippy::cast_possible_truncation)]
             let len = room_id.len() as u32;
             let pt = fn_malloc.call(&mut store, len)?;
 
-            memory.write(&mut store, pt as usize, room_id)?;
+            memory
+                .write(&mut store, pt as usize, room_id)
+                .map_err(|_| WasmRuntimeError::OutOfBoundsMemoryAccess)?;
 
             fn_free.call(&mut store, (pt, len))?;
         }
 
-        if get_global(&mut store, &mut memory, &instance, EXT_STATEROOM_VERSION)
-            .context("Stateroom version")?
-            != EXPECTED_API_VERSION
-        {
-            return Err(WasmRuntimeError::InvalidApiVersion.into());
-        }
-
-        if get_global(&mut store, &mut memory, &instance, EXT_STATEROOM_PROTOCOL)
-            .context("Stateroom protocol")?
-            != EXPECTED_PROTOCOL_VERSION
-        {
-            return Err(WasmRuntimeError::InvalidProtocolVersion.into());
-        }
-
         Ok(WasmHost {
             store,
             memory,
             fn_malloc,
             fn_free,
             fn_recv,
+            protocol: wire_protocol,
+            api_version,
         })
     }
 }
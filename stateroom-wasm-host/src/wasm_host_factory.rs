@@ -1,8 +1,20 @@
-use crate::wasm_host::WasmHost;
+use crate::{
+    capabilities::Capabilities,
+    guest_output::GuestOutput,
+    wasm_host::{linker, HostState, WasmHost, EXPECTED_API_VERSION},
+};
 use anyhow::Result;
-use stateroom::{StateroomContext, StateroomServiceFactory};
-use std::{path::Path, sync::Arc};
-use wasmtime::{Engine, Module};
+use sha2::{Digest, Sha256};
+use stateroom::{OutputLine, ServiceInfo, StateroomContext, StateroomServiceFactory};
+use std::{
+    fs,
+    hash::{Hash, Hasher},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use wasmtime::{
+    Config, Engine, InstanceAllocationStrategy, InstancePre, Module, PoolingAllocationConfig,
+};
 
 /// Loads and caches a WebAssembly module such that a [WasmHost] instance can be
 /// created from it.
@@ -11,8 +23,12 @@ use wasmtime::{Engine, Module};
 /// of the same module.
 #[derive(Clone)]
 pub struct WasmHostFactory {
-    engine: Arc<Engine>,
-    module: Arc<Module>,
+    /// The linked module, or the error from linking it, which is returned when building
+    /// a room.
+    instance_pre: Result<InstancePre<HostState>, Arc<anyhow::Error>>,
+    module_hash: Option<String>,
+    capabilities: Arc<Capabilities>,
+    output: Arc<GuestOutput>,
 }
 
 impl StateroomServiceFactory for WasmHostFactory {
@@ -24,27 +40,343 @@ impl StateroomServiceFactory for WasmHostFactory {
         room_id: &str,
         context: Arc<impl StateroomContext>,
     ) -> Result<Self::Service, Self::Error> {
-        WasmHost::new(room_id, self.module.as_ref(), self.engine.as_ref(), context)
+        let instance_pre = self
+            .instance_pre
+            .as_ref()
+            .map_err(|error| anyhow::anyhow!("Could not link module: {:#}", error))?;
+        WasmHost::from_instance_pre(
+            room_id,
+            instance_pre,
+            &self.capabilities,
+            &self.output,
+            context,
+        )
+    }
+
+    fn service_info(&self) -> ServiceInfo {
+        ServiceInfo {
+            module_hash: self.module_hash.clone(),
+            api_version: Some(EXPECTED_API_VERSION),
+        }
+    }
+
+    fn recent_output(&self, room_id: &str) -> Option<Vec<OutputLine>> {
+        self.output.recent(room_id)
     }
 }
 
+/// The extension of files containing modules precompiled by [WasmHostFactory::precompile].
+pub const PRECOMPILED_EXTENSION: &str = "cwasm";
+
+/// Limits for wasmtime's pooling instance allocator, which reserves memory for a fixed
+/// number of rooms up front so that creating and closing a room doesn't map or unmap
+/// memory.
+///
+/// Each room slot reserves virtual address space for a full 32-bit linear memory, but only
+/// the pages a room touches are committed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolingConfig {
+    /// The maximum number of rooms that can exist at once. Creating a room beyond this
+    /// fails until another room closes.
+    pub max_rooms: u32,
+
+    /// The maximum size of a room's linear memory, in 64 KiB WebAssembly pages. Modules
+    /// whose initial memory is larger than this can't be instantiated. Defaults to the
+    /// 4 GiB that a 32-bit memory can address.
+    pub max_memory_pages: u64,
+}
+
+impl Default for PoolingConfig {
+    fn default() -> Self {
+        PoolingConfig {
+            max_rooms: 1_000,
+            max_memory_pages: 65_536,
+        }
+    }
+}
+
+/// Creates the engine that modules are compiled for. Precompiled and cached modules can
+/// only be loaded by an engine with the same configuration, so every engine created by
+/// this crate comes from here.
+///
+/// The allocation strategy doesn't affect compiled code, so a module precompiled without
+/// a [PoolingConfig] can be loaded by an engine with one.
+pub(crate) fn engine(pooling: Option<PoolingConfig>) -> Result<Engine> {
+    let mut config = Config::new();
+    config.wasm_component_model(true);
+
+    if let Some(pooling) = pooling {
+        let mut pooling_config = PoolingAllocationConfig::default();
+        pooling_config
+            .total_core_instances(pooling.max_rooms)
+            .total_memories(pooling.max_rooms)
+            .total_tables(pooling.max_rooms)
+            .total_gc_heaps(pooling.max_rooms)
+            .max_memory_size((pooling.max_memory_pages * 64 * 1024) as usize);
+        config.allocation_strategy(InstanceAllocationStrategy::Pooling(pooling_config));
+    }
+
+    Engine::new(&config)
+}
+
 impl WasmHostFactory {
     pub fn new<P>(wasm_file: P) -> Result<Self>
     where
         P: AsRef<Path>,
     {
-        let engine = Engine::default();
-        tracing::info!(wasm_file=?wasm_file.as_ref(), "Loading WebAssembly module");
-        let module = Module::from_file(&engine, wasm_file)?;
+        ModuleLoader::new().load(wasm_file)
+    }
+
+    /// Like [WasmHostFactory::new], but keeps compiled modules in `cache_dir`, so that a
+    /// module is only compiled the first time it is loaded. See
+    /// [ModuleLoader::with_cache_dir].
+    pub fn new_with_cache<P, C>(wasm_file: P, cache_dir: C) -> Result<Self>
+    where
+        P: AsRef<Path>,
+        C: AsRef<Path>,
+    {
+        ModuleLoader::new()
+            .with_cache_dir(cache_dir.as_ref())
+            .load(wasm_file)
+    }
 
-        Ok(WasmHostFactory {
-            engine: Arc::new(engine),
-            module: Arc::new(module),
-        })
+    /// Load a module that was compiled ahead of time by [WasmHostFactory::precompile].
+    ///
+    /// The reported module hash is the hash of the precompiled file.
+    ///
+    /// # Safety
+    ///
+    /// See [ModuleLoader::load_precompiled].
+    pub unsafe fn new_precompiled<P>(cwasm_file: P) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        ModuleLoader::new().load_precompiled(cwasm_file)
     }
 
+    /// Compile a WebAssembly module ahead of time, writing the result to `cwasm_file` so
+    /// that it can be loaded with [WasmHostFactory::new_precompiled].
+    pub fn precompile<P, C>(wasm_file: P, cwasm_file: C) -> Result<()>
+    where
+        P: AsRef<Path>,
+        C: AsRef<Path>,
+    {
+        let wasm = fs::read(wasm_file)?;
+        let cwasm = engine(None)?.precompile_module(&wasm)?;
+        fs::write(cwasm_file, cwasm)?;
+        Ok(())
+    }
+
+    /// Create a factory for a module that has already been compiled with `engine`.
+    ///
+    /// If the module has imports that the host doesn't provide, building a room fails.
     #[must_use]
     pub fn new_with_shared_module(engine: Arc<Engine>, module: Arc<Module>) -> Self {
-        WasmHostFactory { engine, module }
+        let module_name = module.name().unwrap_or("module").to_string();
+        let instance_pre = link(&engine, &module).map_err(Arc::new);
+        Self::from_parts(instance_pre, None, &module_name)
+    }
+
+    /// Set the WASI capabilities, such as environment variables and readable directories,
+    /// that are given to each room's guest. By default, a guest gets none.
+    #[must_use]
+    pub fn with_capabilities(mut self, capabilities: Capabilities) -> Self {
+        self.capabilities = Arc::new(capabilities);
+        self
+    }
+
+    fn from_parts(
+        instance_pre: Result<InstancePre<HostState>, Arc<anyhow::Error>>,
+        module_hash: Option<String>,
+        module_name: &str,
+    ) -> Self {
+        WasmHostFactory {
+            instance_pre,
+            module_hash,
+            capabilities: Arc::default(),
+            output: Arc::new(GuestOutput::new(module_name)),
+        }
+    }
+}
+
+/// Options for loading a module into a [WasmHostFactory], for when those of
+/// [WasmHostFactory::new] don't suffice.
+#[derive(Debug, Clone, Default)]
+pub struct ModuleLoader {
+    cache_dir: Option<PathBuf>,
+    pooling: Option<PoolingConfig>,
+}
+
+impl ModuleLoader {
+    #[must_use]
+    pub fn new() -> Self {
+        ModuleLoader::default()
+    }
+
+    /// Keep compiled modules in `cache_dir`, so that a module is only compiled the first
+    /// time it is loaded.
+    ///
+    /// Cache entries are keyed by the module's hash and by the engine's compatibility hash,
+    /// which covers both the engine configuration and the wasmtime version. Entries that
+    /// can't be read are recompiled and replaced; failing to write an entry is not an error.
+    ///
+    /// Cached modules are loaded without validation, so the directory must be trusted and
+    /// private to the server: anyone who can write to it can run code in the server. If it
+    /// doesn't exist, it is created so that on Unix only the current user can access it.
+    #[must_use]
+    pub fn with_cache_dir(mut self, cache_dir: impl Into<PathBuf>) -> Self {
+        self.cache_dir = Some(cache_dir.into());
+        self
+    }
+
+    /// Use wasmtime's pooling instance allocator for the module's rooms, which makes
+    /// creating a room cheaper when rooms are created and closed frequently.
+    #[must_use]
+    pub fn with_pooling_allocator(mut self, pooling: PoolingConfig) -> Self {
+        self.pooling = Some(pooling);
+        self
+    }
+
+    /// Compile a WebAssembly module, or load it from the cache, and link it.
+    pub fn load<P>(&self, wasm_file: P) -> Result<WasmHostFactory>
+    where
+        P: AsRef<Path>,
+    {
+        let engine = engine(self.pooling)?;
+        tracing::info!(wasm_file=?wasm_file.as_ref(), "Loading WebAssembly module");
+        let wasm = fs::read(&wasm_file)?;
+        let module_hash = format!("{:x}", Sha256::digest(&wasm));
+
+        let module = match &self.cache_dir {
+            Some(cache_dir) => load_cached(&engine, wasm, &module_hash, cache_dir)?,
+            None => Module::new(&engine, wasm)?,
+        };
+
+        Ok(WasmHostFactory::from_parts(
+            Ok(link(&engine, &module)?),
+            Some(module_hash),
+            &module_name(wasm_file.as_ref()),
+        ))
+    }
+
+    /// Load a module that was compiled ahead of time by [WasmHostFactory::precompile], and
+    /// link it. The cache directory is not used.
+    ///
+    /// The reported module hash is the hash of the precompiled file.
+    ///
+    /// # Safety
+    ///
+    /// Precompiled modules contain machine code that is run without validation, so the
+    /// file must have been produced by [WasmHostFactory::precompile] (for example, by
+    /// `stateroom build --precompile`) and must be trusted. Files from an incompatible
+    /// engine or wasmtime version are rejected with an error.
+    pub unsafe fn load_precompiled<P>(&self, cwasm_file: P) -> Result<WasmHostFactory>
+    where
+        P: AsRef<Path>,
+    {
+        let engine = engine(self.pooling)?;
+        tracing::info!(cwasm_file=?cwasm_file.as_ref(), "Loading precompiled WebAssembly module");
+        let cwasm = fs::read(&cwasm_file)?;
+        let module_hash = format!("{:x}", Sha256::digest(&cwasm));
+        let module = Module::deserialize(&engine, cwasm)?;
+
+        Ok(WasmHostFactory::from_parts(
+            Ok(link(&engine, &module)?),
+            Some(module_hash),
+            &module_name(cwasm_file.as_ref()),
+        ))
     }
 }
+
+/// Links a module once, so that building a room only needs to instantiate it.
+fn link(engine: &Engine, module: &Module) -> Result<InstancePre<HostState>> {
+    linker(engine)?.instantiate_pre(module)
+}
+
+/// Loads a compiled module from `cache_dir`, or compiles it and adds it to the cache.
+fn load_cached(
+    engine: &Engine,
+    wasm: Vec<u8>,
+    module_hash: &str,
+    cache_dir: &Path,
+) -> Result<Module> {
+    let mut engine_hash = DigestHasher(Sha256::new());
+    engine
+        .precompile_compatibility_hash()
+        .hash(&mut engine_hash);
+    let cache_file = cache_dir.join(format!(
+        "{}-{:x}.{}",
+        module_hash,
+        engine_hash.0.finalize(),
+        PRECOMPILED_EXTENSION
+    ));
+
+    if cache_file.exists() {
+        // SAFETY: the cache directory is trusted (see [ModuleLoader::with_cache_dir]), so
+        // its entries were written by `write_cache_entry` below, from modules compiled by
+        // an engine with a matching compatibility hash.
+        match unsafe { Module::deserialize_file(engine, &cache_file) } {
+            Ok(module) => {
+                tracing::info!(?cache_file, "Loaded compiled module from cache");
+                return Ok(module);
+            }
+            Err(error) => {
+                tracing::warn!(?cache_file, ?error, "Could not load cached module");
+            }
+        }
+    }
+
+    let module = Module::new(engine, wasm)?;
+    if let Err(error) = write_cache_entry(&module, &cache_file) {
+        tracing::warn!(?cache_file, ?error, "Could not write module to cache");
+    }
+    Ok(module)
+}
+
+/// Feeds the bytes that a value hashes to into a SHA-256 digest. Unlike that of
+/// [std::collections::hash_map::DefaultHasher], the result doesn't change between Rust
+/// releases, so it can name files that outlive the process.
+struct DigestHasher(Sha256);
+
+impl Hasher for DigestHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        self.0.update(bytes);
+    }
+
+    fn finish(&self) -> u64 {
+        let digest = self.0.clone().finalize();
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(&digest[..8]);
+        u64::from_le_bytes(bytes)
+    }
+}
+
+/// The name that a module's output is attributed to: its file name without the extension.
+pub(crate) fn module_name(path: &Path) -> String {
+    path.file_stem().map_or_else(
+        || "module".to_string(),
+        |stem| stem.to_string_lossy().into_owned(),
+    )
+}
+
+/// Writes a compiled module to the cache, through a temporary file so that concurrent
+/// readers never see a partially written entry.
+fn write_cache_entry(module: &Module, cache_file: &Path) -> Result<()> {
+    if let Some(cache_dir) = cache_file.parent() {
+        let mut builder = fs::DirBuilder::new();
+        builder.recursive(true);
+        #[cfg(unix)]
+        std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+        builder.create(cache_dir)?;
+    }
+
+    let mut temp_file = PathBuf::from(cache_file);
+    temp_file.set_extension(format!(
+        "{}.{}.tmp",
+        PRECOMPILED_EXTENSION,
+        std::process::id()
+    ));
+    fs::write(&temp_file, module.serialize()?)?;
+    fs::rename(&temp_file, cache_file)?;
+    Ok(())
+}
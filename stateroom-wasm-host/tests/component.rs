@@ -0,0 +1,209 @@
+//! Tests for the component-model host, using a hand-written component in
+//! `tests/component` that targets the `stateroom:service` world, and a component built
+//! by `#[stateroom_wasm(component)]` from `examples/component-echo`.
+
+use stateroom::{
+    ClientId, MessagePayload, MessageRecipient, MessageToProcess, StateroomContext,
+    StateroomServiceFactory,
+};
+use stateroom_wasm_host::{is_component, ComponentHost, ComponentHostFactory};
+use std::sync::{Arc, Mutex};
+
+/// Messages are recorded with the [Debug] representation of their payload, since
+/// [MessagePayload] can't be compared directly.
+#[derive(Default)]
+struct RecordingContext {
+    sent: Mutex<Vec<(MessageRecipient, String)>>,
+    timers: Mutex<Vec<u32>>,
+    close_reason: Mutex<Option<String>>,
+}
+
+impl RecordingContext {
+    fn take_sent(&self) -> Vec<(MessageRecipient, String)> {
+        std::mem::take(&mut self.sent.lock().unwrap())
+    }
+}
+
+fn sent(
+    recipient: MessageRecipient,
+    message: impl Into<MessagePayload>,
+) -> (MessageRecipient, String) {
+    (recipient, format!("{:?}", message.into()))
+}
+
+impl StateroomContext for RecordingContext {
+    fn send_message(
+        &self,
+        recipient: impl Into<MessageRecipient>,
+        message: impl Into<MessagePayload>,
+    ) {
+        self.sent
+            .lock()
+            .unwrap()
+            .push(sent(recipient.into(), message));
+    }
+
+    fn set_timer(&self, ms_delay: u32) {
+        self.timers.lock().unwrap().push(ms_delay);
+    }
+
+    fn connected_clients(&self) -> Vec<ClientId> {
+        vec![ClientId(3), ClientId(258)]
+    }
+
+    fn client_protocol(&self, client: ClientId) -> Option<String> {
+        (client == ClientId(3)).then(|| "chat.v1".to_string())
+    }
+
+    fn close_room(&self, reason: &str) {
+        *self.close_reason.lock().unwrap() = Some(reason.to_string());
+    }
+}
+
+fn build(context: &Arc<RecordingContext>) -> ComponentHost {
+    build_from(
+        &format!("{}/tests/component/echo.wat", env!("CARGO_MANIFEST_DIR")),
+        context,
+    )
+}
+
+fn build_from(path: &str, context: &Arc<RecordingContext>) -> ComponentHost {
+    ComponentHostFactory::new(path)
+        .unwrap()
+        .build("my-room", context.clone())
+        .unwrap()
+}
+
+#[test]
+fn test_component_lifecycle() {
+    let context = Arc::new(RecordingContext::default());
+    let mut host = build(&context);
+
+    host.try_recv(MessageToProcess::Init).unwrap();
+    assert_eq!(
+        context.take_sent(),
+        vec![sent(MessageRecipient::Broadcast, "my-room")]
+    );
+
+    host.try_recv(MessageToProcess::Connect {
+        client: ClientId(3),
+        protocol: None,
+    })
+    .unwrap();
+    host.try_recv(MessageToProcess::Connect {
+        client: ClientId(4),
+        protocol: None,
+    })
+    .unwrap();
+    assert_eq!(
+        context.take_sent(),
+        vec![sent(MessageRecipient::Client(ClientId(3)), "chat.v1")]
+    );
+
+    host.try_recv(MessageToProcess::Message {
+        sender: ClientId(4),
+        message: "hello".into(),
+    })
+    .unwrap();
+    host.try_recv(MessageToProcess::Message {
+        sender: ClientId(4),
+        message: vec![1, 2, 3].into(),
+    })
+    .unwrap();
+    assert_eq!(
+        context.take_sent(),
+        vec![
+            sent(MessageRecipient::Client(ClientId(4)), "hello"),
+            sent(MessageRecipient::Client(ClientId(4)), vec![1, 2, 3]),
+        ]
+    );
+
+    host.try_recv(MessageToProcess::Timer).unwrap();
+    assert_eq!(
+        context.take_sent(),
+        vec![sent(
+            MessageRecipient::Broadcast,
+            vec![3, 0, 0, 0, 2, 1, 0, 0]
+        )]
+    );
+
+    host.try_recv(MessageToProcess::RateLimited {
+        client: ClientId(500),
+    })
+    .unwrap();
+    host.try_recv(MessageToProcess::Disconnect {
+        client: ClientId(4),
+    })
+    .unwrap();
+    assert_eq!(*context.timers.lock().unwrap(), vec![500]);
+    assert_eq!(
+        context.close_reason.lock().unwrap().as_deref(),
+        Some("done")
+    );
+    assert!(context.take_sent().is_empty());
+
+    host.try_recv(MessageToProcess::Shutdown).unwrap();
+    assert_eq!(
+        context.take_sent(),
+        vec![sent(MessageRecipient::Broadcast, "bye")]
+    );
+}
+
+/// Requires the example to be built first, which needs the `wasm32-wasip2` target:
+///
+/// ```bash
+/// $ cargo build --release --target wasm32-wasip2 \
+///     --manifest-path examples/component-echo/Cargo.toml
+/// ```
+#[test]
+#[ignore = "requires examples/component-echo to be built for wasm32-wasip2"]
+fn test_macro_component() {
+    let context = Arc::new(RecordingContext::default());
+    let mut host = build_from(
+        &format!(
+            "{}/../examples/component-echo/target/wasm32-wasip2/release/component_echo.wasm",
+            env!("CARGO_MANIFEST_DIR")
+        ),
+        &context,
+    );
+
+    host.try_recv(MessageToProcess::Init).unwrap();
+    assert_eq!(*context.timers.lock().unwrap(), vec![500]);
+
+    host.try_recv(MessageToProcess::Connect {
+        client: ClientId(3),
+        protocol: None,
+    })
+    .unwrap();
+    host.try_recv(MessageToProcess::Message {
+        sender: ClientId(4),
+        message: vec![1, 2, 3].into(),
+    })
+    .unwrap();
+    host.try_recv(MessageToProcess::Timer).unwrap();
+    assert_eq!(
+        context.take_sent(),
+        vec![
+            sent(MessageRecipient::Client(ClientId(3)), "chat.v1"),
+            sent(MessageRecipient::Client(ClientId(4)), vec![1, 2, 3]),
+            sent(MessageRecipient::Broadcast, "2 clients"),
+        ]
+    );
+
+    host.try_recv(MessageToProcess::Disconnect {
+        client: ClientId(4),
+    })
+    .unwrap();
+    assert_eq!(
+        context.close_reason.lock().unwrap().as_deref(),
+        Some("done")
+    );
+}
+
+#[test]
+fn test_is_component() {
+    assert!(!is_component(b"\0asm\x01\0\0\0"));
+    assert!(is_component(b"\0asm\x0d\0\x01\0"));
+    assert!(!is_component(b"(component)"));
+    assert!(!is_component(&[]));
+}
@@ -0,0 +1,139 @@
+;; A component targeting the stateroom:service world, which reports what it receives:
+;; - init sends the room ID to everyone,
+;; - message echoes the payload back to its sender,
+;; - connect sends the client its negotiated protocol, if it has one,
+;; - timer sends everyone the connected client IDs, as little-endian bytes,
+;; - rate-limited sets a timer with the client ID as its delay,
+;; - disconnect closes the room with the reason "done",
+;; - shutdown sends everyone "bye".
+(component
+  (import "stateroom:service/types" (instance $types
+    (type $payload (variant (case "text" string) (case "bytes" (list u8))))
+    (export "message-payload" (type (eq $payload)))
+    (type $recipient (variant
+      (case "broadcast")
+      (case "client" u32)
+      (case "everyone-except" u32)
+      (case "clients" (list u32))
+      (case "everyone-except-many" (list u32))
+      (case "group" string)))
+    (export "message-recipient" (type (eq $recipient)))))
+  (alias export $types "message-payload" (type $payload))
+  (alias export $types "message-recipient" (type $recipient))
+
+  (import "stateroom:service/context" (instance $context
+    (alias outer 1 $payload (type $payload'))
+    (export "message-payload" (type $payload-ctx (eq $payload')))
+    (alias outer 1 $recipient (type $recipient'))
+    (export "message-recipient" (type $recipient-ctx (eq $recipient')))
+    (export "send-message"
+      (func (param "recipient" $recipient-ctx) (param "message" $payload-ctx)))
+    (export "set-timer" (func (param "ms-delay" u32)))
+    (export "connected-clients" (func (result (list u32))))
+    (export "client-protocol" (func (param "client" u32) (result (option string))))
+    (export "close-room" (func (param "reason" string)))))
+
+  ;; Memory and the allocator live in their own instance, so that imports can be lowered
+  ;; with them before the main module is instantiated.
+  (core module $libc
+    (memory (export "memory") 1)
+    (data (i32.const 512) "donebye")
+    (global $heap (mut i32) (i32.const 1024))
+    (func (export "realloc") (param i32 i32 i32 i32) (result i32)
+      (local $ptr i32)
+      (local.set $ptr
+        (i32.and
+          (i32.add (global.get $heap) (i32.sub (local.get 2) (i32.const 1)))
+          (i32.sub (i32.const 0) (local.get 2))))
+      (global.set $heap (i32.add (local.get $ptr) (local.get 3)))
+      (local.get $ptr)))
+  (core instance $libc (instantiate $libc))
+
+  (core func $send-message (canon lower (func $context "send-message")
+    (memory $libc "memory")))
+  (core func $set-timer (canon lower (func $context "set-timer")))
+  (core func $connected-clients (canon lower (func $context "connected-clients")
+    (memory $libc "memory") (realloc (func $libc "realloc"))))
+  (core func $client-protocol (canon lower (func $context "client-protocol")
+    (memory $libc "memory") (realloc (func $libc "realloc"))))
+  (core func $close-room (canon lower (func $context "close-room")
+    (memory $libc "memory")))
+
+  (core module $main
+    (import "context" "send-message" (func $send (param i32 i32 i32 i32 i32 i32)))
+    (import "context" "set-timer" (func $set-timer (param i32)))
+    (import "context" "connected-clients" (func $clients (param i32)))
+    (import "context" "client-protocol" (func $protocol (param i32 i32)))
+    (import "context" "close-room" (func $close-room (param i32 i32)))
+    (import "libc" "memory" (memory 1))
+
+    (func (export "init") (param $ptr i32) (param $len i32)
+      ;; Broadcast, Text(room ID).
+      (call $send
+        (i32.const 0) (i32.const 0) (i32.const 0)
+        (i32.const 0) (local.get $ptr) (local.get $len)))
+
+    (func (export "connect") (param $client i32)
+      ;; The option is returned at 16: a tag byte, then the string at 20 and 24.
+      (call $protocol (local.get $client) (i32.const 16))
+      (if (i32.load8_u (i32.const 16))
+        (then
+          (call $send
+            (i32.const 1) (local.get $client) (i32.const 0)
+            (i32.const 0) (i32.load (i32.const 20)) (i32.load (i32.const 24))))))
+
+    (func (export "disconnect") (param $client i32)
+      (call $close-room (i32.const 512) (i32.const 4)))
+
+    (func (export "message") (param $sender i32) (param $tag i32) (param $ptr i32) (param $len i32)
+      ;; Client(sender), with the payload unchanged.
+      (call $send
+        (i32.const 1) (local.get $sender) (i32.const 0)
+        (local.get $tag) (local.get $ptr) (local.get $len)))
+
+    (func (export "timer")
+      ;; The list is returned at 32 as a pointer and a length.
+      (call $clients (i32.const 32))
+      ;; Broadcast, Bytes(the list's memory).
+      (call $send
+        (i32.const 0) (i32.const 0) (i32.const 0)
+        (i32.const 1) (i32.load (i32.const 32)) (i32.mul (i32.load (i32.const 36)) (i32.const 4))))
+
+    (func (export "rate-limited") (param $client i32)
+      (call $set-timer (local.get $client)))
+
+    (func (export "shutdown")
+      ;; Broadcast, Text("bye").
+      (call $send
+        (i32.const 0) (i32.const 0) (i32.const 0)
+        (i32.const 0) (i32.const 516) (i32.const 3))))
+
+  (core instance $main (instantiate $main
+    (with "context" (instance
+      (export "send-message" (func $send-message))
+      (export "set-timer" (func $set-timer))
+      (export "connected-clients" (func $connected-clients))
+      (export "client-protocol" (func $client-protocol))
+      (export "close-room" (func $close-room))))
+    (with "libc" (instance $libc))))
+
+  (func $init (param "room-id" string)
+    (canon lift (core func $main "init") (memory $libc "memory") (realloc (func $libc "realloc"))))
+  (func $connect (param "client" u32) (canon lift (core func $main "connect")))
+  (func $disconnect (param "client" u32) (canon lift (core func $main "disconnect")))
+  (func $message (param "sender" u32) (param "message" $payload)
+    (canon lift (core func $main "message") (memory $libc "memory") (realloc (func $libc "realloc"))))
+  (func $timer (canon lift (core func $main "timer")))
+  (func $rate-limited (param "client" u32) (canon lift (core func $main "rate-limited")))
+  (func $shutdown (canon lift (core func $main "shutdown")))
+
+  (instance $handler
+    (export "message-payload" (type $payload))
+    (export "init" (func $init))
+    (export "connect" (func $connect))
+    (export "disconnect" (func $disconnect))
+    (export "message" (func $message))
+    (export "timer" (func $timer))
+    (export "rate-limited" (func $rate-limited))
+    (export "shutdown" (func $shutdown)))
+  (export "stateroom:service/handler" (instance $handler)))
@@ -0,0 +1,386 @@
+//! ABI conformance tests, built from hand-written WebAssembly text modules in
+//! `tests/conformance`. Each module exercises one part of the host interface; the host
+//! must either behave correctly or fail with a specific [WasmRuntimeError].
+
+use stateroom::{
+    ClientId, MessagePayload, MessageRecipient, MessageToProcess, OutputSource, StateroomContext,
+    StateroomServiceFactory,
+};
+use stateroom_wasm_host::{
+    Capabilities, ModuleLoader, PoolingConfig, WasmHost, WasmHostFactory, WasmRuntimeError,
+};
+use std::sync::{Arc, Mutex};
+use wasmtime::{Engine, Module};
+
+#[derive(Default)]
+struct RecordingContext {
+    clients: Vec<ClientId>,
+    sent: Mutex<Vec<(MessageRecipient, MessagePayload)>>,
+}
+
+impl RecordingContext {
+    fn with_clients(clients: &[u32]) -> Self {
+        RecordingContext {
+            clients: clients.iter().copied().map(ClientId).collect(),
+            ..RecordingContext::default()
+        }
+    }
+
+    fn sent_text(&self) -> Vec<String> {
+        self.sent
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(recipient, message)| {
+                assert_eq!(recipient, &MessageRecipient::Broadcast);
+                message
+                    .text()
+                    .expect("Expected a text message.")
+                    .to_string()
+            })
+            .collect()
+    }
+}
+
+impl StateroomContext for RecordingContext {
+    fn send_message(
+        &self,
+        recipient: impl Into<MessageRecipient>,
+        message: impl Into<MessagePayload>,
+    ) {
+        self.sent
+            .lock()
+            .unwrap()
+            .push((recipient.into(), message.into()));
+    }
+
+    fn set_timer(&self, _: u32) {}
+
+    fn connected_clients(&self) -> Vec<ClientId> {
+        self.clients.clone()
+    }
+}
+
+fn build(name: &str, room_id: &str, context: &Arc<RecordingContext>) -> anyhow::Result<WasmHost> {
+    let path = format!(
+        "{}/tests/conformance/{}.wat",
+        env!("CARGO_MANIFEST_DIR"),
+        name
+    );
+    WasmHostFactory::new(path)?.build(room_id, context.clone())
+}
+
+fn runtime_error(error: anyhow::Error) -> WasmRuntimeError {
+    match error.downcast::<WasmRuntimeError>() {
+        Ok(error) => error,
+        Err(error) => panic!("Expected a WasmRuntimeError, got {:?}", error),
+    }
+}
+
+fn message(text: &str) -> MessageToProcess {
+    MessageToProcess::Message {
+        sender: ClientId(1),
+        message: text.into(),
+    }
+}
+
+#[test]
+fn test_json_send() {
+    let context = Arc::new(RecordingContext::default());
+    let mut host = build("json_send", "room", &context).unwrap();
+
+    host.try_recv(MessageToProcess::Init).unwrap();
+    host.try_recv(message("ping")).unwrap();
+
+    assert_eq!(context.sent_text(), vec!["pong", "pong"]);
+}
+
+#[test]
+fn test_bincode_send() {
+    let context = Arc::new(RecordingContext::default());
+    let mut host = build("bincode_send", "room", &context).unwrap();
+
+    host.try_recv(MessageToProcess::Init).unwrap();
+
+    assert_eq!(context.sent_text(), vec!["pong"]);
+}
+
+/// Returns the encoding of `Connect` that a module built against `name` receives.
+fn received_connect(name: &str) -> Vec<u8> {
+    let context = Arc::new(RecordingContext::default());
+    let mut host = build(name, "room", &context).unwrap();
+
+    host.try_recv(MessageToProcess::Connect {
+        client: ClientId(1),
+        protocol: Some("chat".to_string()),
+    })
+    .unwrap();
+
+    let sent = context.sent.lock().unwrap();
+    sent.first().unwrap().1.bytes().unwrap().to_vec()
+}
+
+#[test]
+fn test_api_versions() {
+    let connect = MessageToProcess::Connect {
+        client: ClientId(1),
+        protocol: Some("chat".to_string()),
+    };
+    assert_eq!(
+        received_connect("echo"),
+        bincode::serialize(&connect).unwrap()
+    );
+    assert_eq!(received_connect("echo_v1"), [1, 0, 0, 0, 1, 0, 0, 0]);
+
+    // Version 1 modules can't decode shutdown, so it isn't delivered to them.
+    let context = Arc::new(RecordingContext::default());
+    let mut host = build("echo_v1", "room", &context).unwrap();
+    host.try_recv(MessageToProcess::Shutdown).unwrap();
+    assert!(context.sent.lock().unwrap().is_empty());
+}
+
+#[test]
+fn test_malloc_free() {
+    let context = Arc::new(RecordingContext::default());
+    let mut host = build("malloc_free", "room", &context).unwrap();
+
+    host.try_recv(MessageToProcess::Init).unwrap();
+    host.try_recv(message("")).unwrap();
+    host.try_recv(MessageToProcess::Timer).unwrap();
+
+    assert_eq!(context.sent_text(), vec!["ok", "ok", "ok"]);
+}
+
+#[test]
+fn test_zero_length_room_id() {
+    let context = Arc::new(RecordingContext::default());
+    let mut host = build("malloc_free", "", &context).unwrap();
+
+    host.try_recv(MessageToProcess::Init).unwrap();
+
+    assert_eq!(context.sent_text(), vec!["ok"]);
+}
+
+#[test]
+fn test_reentrant_send() {
+    let context = Arc::new(RecordingContext::default());
+    let mut host = build("reentrant_send", "room", &context).unwrap();
+    // The room ID is passed through stateroom_malloc when the host is built.
+    assert_eq!(context.sent_text(), vec!["malloc"]);
+
+    host.try_recv(MessageToProcess::Init).unwrap();
+
+    assert_eq!(context.sent_text(), vec!["malloc", "malloc", "a", "b", "c"]);
+}
+
+#[test]
+fn test_connected_clients() {
+    let context = Arc::new(RecordingContext::with_clients(&[3, 7]));
+    let mut host = build("connected_clients", "room", &context).unwrap();
+
+    host.try_recv(MessageToProcess::Init).unwrap();
+
+    assert_eq!(context.sent_text(), vec!["ok"]);
+}
+
+#[test]
+fn test_zero_length_send() {
+    let context = Arc::new(RecordingContext::default());
+    let mut host = build("zero_length_send", "room", &context).unwrap();
+
+    let error = host.try_recv(MessageToProcess::Init).unwrap_err();
+
+    assert_eq!(runtime_error(error), WasmRuntimeError::InvalidMessage);
+}
+
+#[test]
+fn test_invalid_message() {
+    let context = Arc::new(RecordingContext::default());
+    let mut host = build("invalid_message", "room", &context).unwrap();
+
+    let error = host.try_recv(MessageToProcess::Init).unwrap_err();
+
+    assert_eq!(runtime_error(error), WasmRuntimeError::InvalidMessage);
+}
+
+#[test]
+fn test_out_of_bounds_send() {
+    let context = Arc::new(RecordingContext::default());
+    let mut host = build("oob_send", "room", &context).unwrap();
+
+    let error = host.try_recv(MessageToProcess::Init).unwrap_err();
+
+    assert_eq!(
+        runtime_error(error),
+        WasmRuntimeError::OutOfBoundsMemoryAccess
+    );
+}
+
+#[test]
+fn test_out_of_bounds_malloc() {
+    let context = Arc::new(RecordingContext::default());
+    let error = build("oob_malloc", "room", &context).err().unwrap();
+
+    assert_eq!(
+        runtime_error(error),
+        WasmRuntimeError::OutOfBoundsMemoryAccess
+    );
+}
+
+#[test]
+fn test_out_of_bounds_connected_clients() {
+    let context = Arc::new(RecordingContext::with_clients(&[3, 7]));
+    let mut host = build("oob_connected_clients", "room", &context).unwrap();
+
+    let error = host.try_recv(MessageToProcess::Init).unwrap_err();
+
+    assert_eq!(
+        runtime_error(error),
+        WasmRuntimeError::OutOfBoundsMemoryAccess
+    );
+}
+
+#[test]
+fn test_wrong_api_version() {
+    let context = Arc::new(RecordingContext::default());
+    let error = build("wrong_api_version", "room", &context).err().unwrap();
+
+    assert_eq!(runtime_error(error), WasmRuntimeError::InvalidApiVersion);
+}
+
+#[test]
+fn test_wrong_protocol() {
+    let context = Arc::new(RecordingContext::default());
+    let error = build("wrong_protocol", "room", &context).err().unwrap();
+
+    assert_eq!(
+        runtime_error(error),
+        WasmRuntimeError::InvalidProtocolVersion
+    );
+}
+
+#[test]
+fn test_missing_global() {
+    let context = Arc::new(RecordingContext::default());
+    let error = build("missing_global", "room", &context).err().unwrap();
+
+    assert_eq!(runtime_error(error), WasmRuntimeError::CouldNotImportGlobal);
+}
+
+#[test]
+fn test_out_of_bounds_global() {
+    let context = Arc::new(RecordingContext::default());
+    let error = build("oob_global", "room", &context).err().unwrap();
+
+    assert_eq!(runtime_error(error), WasmRuntimeError::CouldNotImportGlobal);
+}
+
+#[test]
+fn test_missing_memory() {
+    let context = Arc::new(RecordingContext::default());
+    let error = build("missing_memory", "room", &context).err().unwrap();
+
+    assert_eq!(runtime_error(error), WasmRuntimeError::CouldNotImportMemory);
+}
+
+#[test]
+fn test_oversized_send() {
+    let context = Arc::new(RecordingContext::default());
+    let mut host = build("oversized_send", "room", &context).unwrap();
+
+    let error = host.try_recv(MessageToProcess::Init).unwrap_err();
+
+    assert_eq!(runtime_error(error), WasmRuntimeError::MessageTooLarge);
+}
+
+#[test]
+fn test_pooling_allocator() {
+    let context = Arc::new(RecordingContext::default());
+    let path = format!(
+        "{}/tests/conformance/json_send.wat",
+        env!("CARGO_MANIFEST_DIR")
+    );
+    let factory = ModuleLoader::new()
+        .with_pooling_allocator(PoolingConfig {
+            max_rooms: 1,
+            ..PoolingConfig::default()
+        })
+        .load(path)
+        .unwrap();
+
+    let mut host = factory.build("room", context.clone()).unwrap();
+    host.try_recv(MessageToProcess::Init).unwrap();
+    assert_eq!(context.sent_text(), vec!["pong"]);
+
+    // The pool has one slot, which is released when the room is dropped.
+    assert!(factory.build("room", context.clone()).is_err());
+    drop(host);
+    factory.build("room", context.clone()).unwrap();
+}
+
+#[test]
+fn test_shared_module_link_error() {
+    let context = Arc::new(RecordingContext::default());
+    let engine = Engine::default();
+    let module = Module::new(&engine, r#"(module (import "env" "missing" (func)))"#).unwrap();
+
+    // The factory is created, but the missing import is reported when building a room.
+    let factory = WasmHostFactory::new_with_shared_module(Arc::new(engine), Arc::new(module));
+    assert!(factory.build("room", context).is_err());
+}
+
+#[test]
+fn test_capabilities() {
+    let context = Arc::new(RecordingContext::default());
+    let path = format!(
+        "{}/tests/conformance/wasi_environ.wat",
+        env!("CARGO_MANIFEST_DIR")
+    );
+    let mut capabilities = Capabilities::default();
+    capabilities
+        .env
+        .insert("GREETING".to_string(), "hello".to_string());
+    let factory = WasmHostFactory::new(path)
+        .unwrap()
+        .with_capabilities(capabilities);
+
+    let mut host = factory.build("room", context.clone()).unwrap();
+    host.try_recv(MessageToProcess::Init).unwrap();
+
+    assert_eq!(context.sent_text(), vec!["GREETING=hello"]);
+}
+
+#[test]
+fn test_captured_output() {
+    let context = Arc::new(RecordingContext::default());
+    let path = format!(
+        "{}/tests/conformance/fd_write.wat",
+        env!("CARGO_MANIFEST_DIR")
+    );
+    let factory = WasmHostFactory::new(path).unwrap();
+    let recent_output = |room_id| {
+        factory.recent_output(room_id).map(|lines| {
+            lines
+                .into_iter()
+                .map(|line| (line.source, line.text))
+                .collect::<Vec<_>>()
+        })
+    };
+
+    let mut host = factory.build("room", context.clone()).unwrap();
+    host.try_recv(MessageToProcess::Init).unwrap();
+    host.try_recv(MessageToProcess::Timer).unwrap();
+
+    // Output is split into lines, and the incomplete line on stderr is held back.
+    assert_eq!(
+        recent_output("room").unwrap(),
+        vec![
+            (OutputSource::Stdout, "hello, world".to_string()),
+            (OutputSource::Stdout, "hello, world".to_string()),
+        ]
+    );
+    assert!(recent_output("other-room").is_none());
+
+    drop(host);
+    assert!(recent_output("room").is_none());
+}
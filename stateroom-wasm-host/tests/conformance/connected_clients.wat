@@ -0,0 +1,25 @@
+;; Asks the host for the connected clients, first for the count alone and then for
+;; the IDs, and traps unless they are exactly [3, 7]. Acknowledges with "ok".
+(module
+  (import "env" "stateroom_send" (func $send (param i32 i32)))
+  (import "env" "stateroom_connected_clients" (func $clients (param i32 i32) (result i32)))
+
+  (memory (export "memory") 1)
+  (data (i32.const 0) "\01\00\00\00")
+  (data (i32.const 4) "\01\00\00\00")
+  (data (i32.const 64) "{\"Message\":{\"recipient\":\"Broadcast\",\"message\":{\"Text\":\"ok\"}}}")
+
+  (global (export "STATEROOM_API_VERSION") i32 (i32.const 0))
+  (global (export "STATEROOM_API_PROTOCOL") i32 (i32.const 4))
+
+  (func (export "stateroom_malloc") (param $size i32) (result i32)
+    (i32.const 1024))
+
+  (func (export "stateroom_free") (param i32 i32))
+
+  (func (export "stateroom_recv") (param i32 i32)
+    (if (i32.ne (call $clients (i32.const 0) (i32.const 0)) (i32.const 2)) (then unreachable))
+    (if (i32.ne (call $clients (i32.const 2048) (i32.const 4)) (i32.const 2)) (then unreachable))
+    (if (i32.ne (i32.load (i32.const 2048)) (i32.const 3)) (then unreachable))
+    (if (i32.ne (i32.load (i32.const 2052)) (i32.const 7)) (then unreachable))
+    (call $send (i32.const 64) (i32.const 61))))
@@ -0,0 +1,25 @@
+;; Echoes every message from the host back to it as a bincode-encoded broadcast of its bytes.
+(module
+  (import "env" "stateroom_send" (func $send (param i32 i32)))
+
+  (memory (export "memory") 1)
+  (data (i32.const 0) "\02\00\00\00")
+  (data (i32.const 4) "\00\00\00\00")
+  ;; MessageFromProcess::Message { recipient: Broadcast, message: Bytes(..) }, followed
+  ;; by the length of the bytes, which the received message follows at 1024.
+  (data (i32.const 1004)
+    "\00\00\00\00"
+    "\00\00\00\00"
+    "\00\00\00\00")
+
+  (global (export "STATEROOM_API_VERSION") i32 (i32.const 0))
+  (global (export "STATEROOM_API_PROTOCOL") i32 (i32.const 4))
+
+  (func (export "stateroom_malloc") (param $size i32) (result i32)
+    (i32.const 1024))
+
+  (func (export "stateroom_free") (param i32 i32))
+
+  (func (export "stateroom_recv") (param $ptr i32) (param $len i32)
+    (i64.store (i32.const 1016) (i64.extend_i32_u (local.get $len)))
+    (call $send (i32.const 1004) (i32.add (local.get $len) (i32.const 20)))))
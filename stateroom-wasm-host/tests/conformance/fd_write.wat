@@ -0,0 +1,26 @@
+;; Writes a line to standard output, in two calls, and half a line to standard error on
+;; every message from the host.
+(module
+  (import "wasi_snapshot_preview1" "fd_write"
+    (func $fd_write (param i32 i32 i32 i32) (result i32)))
+
+  (memory (export "memory") 1)
+  (data (i32.const 0) "\01\00\00\00")
+  (data (i32.const 4) "\01\00\00\00")
+  (data (i32.const 64) "hello, world\nwarn")
+  ;; iovecs: "hello, " and "world\n" to stdout, then "warn" to stderr. One iovec is
+  ;; passed per call, since `fd_write` may stop after the first.
+  (data (i32.const 128) "\40\00\00\00\07\00\00\00\47\00\00\00\06\00\00\00\4d\00\00\00\04\00\00\00")
+
+  (global (export "STATEROOM_API_VERSION") i32 (i32.const 0))
+  (global (export "STATEROOM_API_PROTOCOL") i32 (i32.const 4))
+
+  (func (export "stateroom_malloc") (param $size i32) (result i32)
+    (i32.const 1024))
+
+  (func (export "stateroom_free") (param i32 i32))
+
+  (func (export "stateroom_recv") (param i32 i32)
+    (drop (call $fd_write (i32.const 1) (i32.const 128) (i32.const 1) (i32.const 256)))
+    (drop (call $fd_write (i32.const 1) (i32.const 136) (i32.const 1) (i32.const 256)))
+    (drop (call $fd_write (i32.const 2) (i32.const 144) (i32.const 1) (i32.const 256)))))
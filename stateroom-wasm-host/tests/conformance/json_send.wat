@@ -0,0 +1,19 @@
+;; Protocol 1: replies to every message from the host with a JSON broadcast.
+(module
+  (import "env" "stateroom_send" (func $send (param i32 i32)))
+
+  (memory (export "memory") 1)
+  (data (i32.const 0) "\01\00\00\00")
+  (data (i32.const 4) "\01\00\00\00")
+  (data (i32.const 64) "{\"Message\":{\"recipient\":\"Broadcast\",\"message\":{\"Text\":\"pong\"}}}")
+
+  (global (export "STATEROOM_API_VERSION") i32 (i32.const 0))
+  (global (export "STATEROOM_API_PROTOCOL") i32 (i32.const 4))
+
+  (func (export "stateroom_malloc") (param $size i32) (result i32)
+    (i32.const 1024))
+
+  (func (export "stateroom_free") (param i32 i32))
+
+  (func (export "stateroom_recv") (param i32 i32)
+    (call $send (i32.const 64) (i32.const 63))))
@@ -0,0 +1,39 @@
+;; Checks that the host passes messages in memory it allocated with stateroom_malloc,
+;; and frees each allocation exactly once with the same pointer and size. Any
+;; violation traps; otherwise each message is acknowledged with "ok".
+(module
+  (import "env" "stateroom_send" (func $send (param i32 i32)))
+
+  (memory (export "memory") 1)
+  (data (i32.const 0) "\01\00\00\00")
+  (data (i32.const 4) "\01\00\00\00")
+  (data (i32.const 64) "{\"Message\":{\"recipient\":\"Broadcast\",\"message\":{\"Text\":\"ok\"}}}")
+
+  (global (export "STATEROOM_API_VERSION") i32 (i32.const 0))
+  (global (export "STATEROOM_API_PROTOCOL") i32 (i32.const 4))
+
+  (global $next (mut i32) (i32.const 1024))
+  (global $outstanding (mut i32) (i32.const 0))
+  (global $last_ptr (mut i32) (i32.const 0))
+  (global $last_size (mut i32) (i32.const 0))
+
+  (func (export "stateroom_malloc") (param $size i32) (result i32)
+    (if (global.get $outstanding) (then unreachable))
+    (global.set $outstanding (i32.const 1))
+    (global.set $last_ptr (global.get $next))
+    (global.set $last_size (local.get $size))
+    (global.set $next (i32.add (global.get $next) (local.get $size)))
+    (global.get $last_ptr))
+
+  (func (export "stateroom_free") (param $ptr i32) (param $size i32)
+    (if (i32.eqz (global.get $outstanding)) (then unreachable))
+    (if (i32.ne (local.get $ptr) (global.get $last_ptr)) (then unreachable))
+    (if (i32.ne (local.get $size) (global.get $last_size)) (then unreachable))
+    (global.set $outstanding (i32.const 0)))
+
+  (func (export "stateroom_recv") (param $ptr i32) (param $len i32)
+    (if (i32.eqz (global.get $outstanding)) (then unreachable))
+    (if (i32.ne (local.get $ptr) (global.get $last_ptr)) (then unreachable))
+    (if (i32.ne (local.get $len) (global.get $last_size)) (then unreachable))
+    (if (i32.eqz (local.get $len)) (then unreachable))
+    (call $send (i32.const 64) (i32.const 61))))
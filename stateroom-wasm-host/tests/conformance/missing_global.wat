@@ -0,0 +1,13 @@
+;; Does not export STATEROOM_API_PROTOCOL.
+(module
+  (memory (export "memory") 1)
+  (data (i32.const 0) "\01\00\00\00")
+
+  (global (export "STATEROOM_API_VERSION") i32 (i32.const 0))
+
+  (func (export "stateroom_malloc") (param $size i32) (result i32)
+    (i32.const 1024))
+
+  (func (export "stateroom_free") (param i32 i32))
+
+  (func (export "stateroom_recv") (param i32 i32)))
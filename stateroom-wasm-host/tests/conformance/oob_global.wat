@@ -0,0 +1,14 @@
+;; Exports a version global that points past the end of memory.
+(module
+  (memory (export "memory") 1)
+  (data (i32.const 4) "\01\00\00\00")
+
+  (global (export "STATEROOM_API_VERSION") i32 (i32.const 0xfffe))
+  (global (export "STATEROOM_API_PROTOCOL") i32 (i32.const 4))
+
+  (func (export "stateroom_malloc") (param $size i32) (result i32)
+    (i32.const 1024))
+
+  (func (export "stateroom_free") (param i32 i32))
+
+  (func (export "stateroom_recv") (param i32 i32)))
@@ -0,0 +1,31 @@
+;; Protocol 1: broadcasts its single WASI environment variable, as `KEY=value`, on every
+;; message from the host.
+(module
+  (import "wasi_snapshot_preview1" "environ_sizes_get"
+    (func $environ_sizes_get (param i32 i32) (result i32)))
+  (import "wasi_snapshot_preview1" "environ_get"
+    (func $environ_get (param i32 i32) (result i32)))
+  (import "env" "stateroom_send" (func $send (param i32 i32)))
+
+  (memory (export "memory") 1)
+  (data (i32.const 0) "\01\00\00\00")
+  (data (i32.const 4) "\01\00\00\00")
+  ;; The variable is written between this prefix and a closing `"}}}`.
+  (data (i32.const 4096) "{\"Message\":{\"recipient\":\"Broadcast\",\"message\":{\"Text\":\"")
+
+  (global (export "STATEROOM_API_VERSION") i32 (i32.const 0))
+  (global (export "STATEROOM_API_PROTOCOL") i32 (i32.const 4))
+
+  (func (export "stateroom_malloc") (param $size i32) (result i32)
+    (i32.const 1024))
+
+  (func (export "stateroom_free") (param i32 i32))
+
+  (func (export "stateroom_recv") (param i32 i32)
+    (local $len i32)
+    (drop (call $environ_sizes_get (i32.const 64) (i32.const 68)))
+    (drop (call $environ_get (i32.const 128) (i32.const 4151)))
+    ;; The buffer size includes the variable's NUL terminator, which is overwritten.
+    (local.set $len (i32.sub (i32.load (i32.const 68)) (i32.const 1)))
+    (i32.store (i32.add (i32.const 4151) (local.get $len)) (i32.const 0x7d7d7d22))
+    (call $send (i32.const 4096) (i32.add (local.get $len) (i32.const 59)))))
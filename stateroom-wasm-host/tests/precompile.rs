@@ -0,0 +1,56 @@
+use stateroom_wasm_host::{ModuleLoader, PoolingConfig, WasmHostFactory, PRECOMPILED_EXTENSION};
+use std::{fs, path::PathBuf};
+
+fn module_path() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/conformance/json_send.wat")
+}
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("stateroom-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+#[test]
+fn test_module_cache() {
+    let cache_dir = temp_dir("module-cache");
+
+    WasmHostFactory::new_with_cache(module_path(), &cache_dir).unwrap();
+    let entries: Vec<_> = fs::read_dir(&cache_dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].extension().unwrap(), PRECOMPILED_EXTENSION);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(&cache_dir).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+    }
+
+    // A second load is served from the cache, and a corrupt entry is replaced.
+    WasmHostFactory::new_with_cache(module_path(), &cache_dir).unwrap();
+    fs::write(&entries[0], b"not a module").unwrap();
+    WasmHostFactory::new_with_cache(module_path(), &cache_dir).unwrap();
+    assert_ne!(fs::read(&entries[0]).unwrap(), b"not a module");
+
+    fs::remove_dir_all(&cache_dir).unwrap();
+}
+
+#[test]
+fn test_precompile() {
+    let dir = temp_dir("precompile");
+    fs::create_dir_all(&dir).unwrap();
+    let cwasm_file = dir.join("server.cwasm");
+
+    WasmHostFactory::precompile(module_path(), &cwasm_file).unwrap();
+    unsafe { WasmHostFactory::new_precompiled(&cwasm_file) }.unwrap();
+    let pooled = ModuleLoader::new().with_pooling_allocator(PoolingConfig::default());
+    unsafe { pooled.load_precompiled(&cwasm_file) }.unwrap();
+
+    fs::write(&cwasm_file, b"not a module").unwrap();
+    assert!(unsafe { WasmHostFactory::new_precompiled(&cwasm_file) }.is_err());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,144 @@
+//! Support for building a service as a WebAssembly component that targets the
+//! `stateroom:service` world defined in `wit/stateroom.wit`. Services opt into it with
+//! `#[stateroom_wasm(component)]`, which uses the items in this module.
+
+use self::stateroom::service::context;
+use crate::{ClientId, MessagePayload, MessageRecipient, StateroomContext, StateroomService};
+
+wit_bindgen::generate!({
+    path: "wit",
+    world: "service",
+    pub_export_macro: true,
+    export_macro_name: "export_component",
+    default_bindings_module: "stateroom_wasm::component",
+});
+
+pub use self::exports::stateroom::service::handler::Guest;
+pub use self::stateroom::service::types;
+
+/// Adapts a [StateroomService] to the functions exported by a component.
+pub struct ComponentStateroomService<S: StateroomService> {
+    state: S,
+}
+
+impl<S: StateroomService> ComponentStateroomService<S> {
+    pub fn new(state: S) -> Self {
+        Self { state }
+    }
+
+    pub fn init(&mut self) {
+        self.state.init(&ComponentStateroomContext);
+    }
+
+    pub fn connect(&mut self, client: types::ClientId) {
+        self.state
+            .connect(ClientId(client), &ComponentStateroomContext);
+    }
+
+    pub fn disconnect(&mut self, client: types::ClientId) {
+        self.state
+            .disconnect(ClientId(client), &ComponentStateroomContext);
+    }
+
+    pub fn message(&mut self, sender: types::ClientId, message: types::MessagePayload) {
+        self.state
+            .message(ClientId(sender), message.into(), &ComponentStateroomContext);
+    }
+
+    pub fn timer(&mut self) {
+        self.state.timer(&ComponentStateroomContext);
+    }
+
+    pub fn rate_limited(&mut self, client: types::ClientId) {
+        self.state
+            .rate_limited(ClientId(client), &ComponentStateroomContext);
+    }
+
+    pub fn shutdown(&mut self) {
+        self.state.shutdown(&ComponentStateroomContext);
+    }
+}
+
+/// A [StateroomContext] that calls the functions imported from `stateroom:service/context`.
+struct ComponentStateroomContext;
+
+impl StateroomContext for ComponentStateroomContext {
+    fn send_message(
+        &self,
+        recipient: impl Into<MessageRecipient>,
+        message: impl Into<MessagePayload>,
+    ) {
+        let recipient: MessageRecipient = recipient.into();
+        let message: MessagePayload = message.into();
+
+        context::send_message(&recipient.into(), &message.into());
+    }
+
+    fn set_timer(&self, ms_delay: u32) {
+        context::set_timer(ms_delay);
+    }
+
+    fn join_group(&self, client: ClientId, group: &str) {
+        context::join_group(client.0, group);
+    }
+
+    fn leave_group(&self, client: ClientId, group: &str) {
+        context::leave_group(client.0, group);
+    }
+
+    fn connected_clients(&self) -> Vec<ClientId> {
+        context::connected_clients()
+            .into_iter()
+            .map(ClientId)
+            .collect()
+    }
+
+    fn client_protocol(&self, client: ClientId) -> Option<String> {
+        context::client_protocol(client.0)
+    }
+
+    fn close_room(&self, reason: &str) {
+        context::close_room(reason);
+    }
+}
+
+// Payloads are moved as they are, unless the `bytes` feature of `stateroom` is enabled.
+
+impl From<types::MessagePayload> for MessagePayload {
+    #[allow(clippy::useless_conversion)]
+    fn from(message: types::MessagePayload) -> Self {
+        match message {
+            types::MessagePayload::Text(text) => MessagePayload::Text(text.into()),
+            types::MessagePayload::Bytes(bytes) => MessagePayload::Bytes(bytes.into()),
+        }
+    }
+}
+
+impl From<MessagePayload> for types::MessagePayload {
+    #[allow(clippy::useless_conversion)]
+    fn from(message: MessagePayload) -> Self {
+        match message {
+            MessagePayload::Text(text) => types::MessagePayload::Text(text.into()),
+            MessagePayload::Bytes(bytes) => types::MessagePayload::Bytes(bytes.into()),
+        }
+    }
+}
+
+impl From<MessageRecipient> for types::MessageRecipient {
+    fn from(recipient: MessageRecipient) -> Self {
+        let clients = |clients: Vec<ClientId>| clients.into_iter().map(|c| c.0).collect();
+
+        match recipient {
+            MessageRecipient::Broadcast => types::MessageRecipient::Broadcast,
+            MessageRecipient::Client(client) => types::MessageRecipient::Client(client.0),
+            MessageRecipient::EveryoneExcept(client) => {
+                types::MessageRecipient::EveryoneExcept(client.0)
+            }
+            MessageRecipient::Clients(list) => types::MessageRecipient::Clients(clients(list)),
+            MessageRecipient::EveryoneExceptMany(list) => {
+                types::MessageRecipient::EveryoneExceptMany(clients(list))
+            }
+            MessageRecipient::Group(group) => types::MessageRecipient::Group(group),
+        }
+    }
+}
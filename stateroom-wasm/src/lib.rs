@@ -2,8 +2,13 @@ use stateroom::MessageFromProcess;
 pub use stateroom::{ClientId, MessageRecipient, StateroomContext, StateroomService};
 pub use stateroom::{MessagePayload, MessageToProcess};
 pub use stateroom_wasm_macro::stateroom_wasm;
+use std::{collections::HashMap, sync::Mutex};
+
+#[cfg(feature = "component")]
+pub mod component;
 
 type Callback = unsafe extern "C" fn(*const u8, u32);
+type ClientsCallback = unsafe extern "C" fn(*mut u32, u32) -> u32;
 
 pub struct WrappedStateroomService<S: StateroomService> {
     state: S,
@@ -11,10 +16,14 @@ pub struct WrappedStateroomService<S: StateroomService> {
 }
 
 impl<S: StateroomService> WrappedStateroomService<S> {
-    pub fn new(state: S, callback: Callback) -> Self {
+    pub fn new(state: S, callback: Callback, clients_callback: ClientsCallback) -> Self {
         Self {
             state,
-            context: WasmStateroomContext { callback },
+            context: WasmStateroomContext {
+                callback,
+                clients_callback,
+                protocols: Mutex::default(),
+            },
         }
     }
 
@@ -26,11 +35,19 @@ impl<S: StateroomService> WrappedStateroomService<S> {
             MessageToProcess::Init => {
                 self.state.init(&self.context);
             }
-            MessageToProcess::Connect { client } => {
-                self.state.connect(client.into(), &self.context);
+            MessageToProcess::Connect { client, protocol } => {
+                if let Some(protocol) = protocol {
+                    self.context
+                        .protocols
+                        .lock()
+                        .unwrap()
+                        .insert(client, protocol);
+                }
+                self.state.connect(client, &self.context);
             }
             MessageToProcess::Disconnect { client } => {
-                self.state.disconnect(client.into(), &self.context);
+                self.state.disconnect(client, &self.context);
+                self.context.protocols.lock().unwrap().remove(&client);
             }
             MessageToProcess::Message { sender, message } => {
                 self.state.message(sender, message, &self.context);
@@ -38,12 +55,20 @@ impl<S: StateroomService> WrappedStateroomService<S> {
             MessageToProcess::Timer => {
                 self.state.timer(&self.context);
             }
+            MessageToProcess::RateLimited { client } => {
+                self.state.rate_limited(client, &self.context);
+            }
+            MessageToProcess::Shutdown => {
+                self.state.shutdown(&self.context);
+            }
         }
     }
 }
 
 struct WasmStateroomContext {
     callback: Callback,
+    clients_callback: ClientsCallback,
+    protocols: Mutex<HashMap<ClientId, String>>,
 }
 
 impl WasmStateroomContext {
@@ -70,4 +95,90 @@ impl StateroomContext for WasmStateroomContext {
     fn set_timer(&self, ms_delay: u32) {
         self.send(&MessageFromProcess::SetTimer { ms_delay });
     }
+
+    fn join_group(&self, client: ClientId, group: &str) {
+        self.send(&MessageFromProcess::JoinGroup {
+            client,
+            group: group.to_string(),
+        });
+    }
+
+    fn leave_group(&self, client: ClientId, group: &str) {
+        self.send(&MessageFromProcess::LeaveGroup {
+            client,
+            group: group.to_string(),
+        });
+    }
+
+    fn connected_clients(&self) -> Vec<ClientId> {
+        let mut buffer: Vec<u32> = Vec::new();
+        loop {
+            // The host writes at most `buffer.len()` client IDs and returns the total count,
+            // so retry with a larger buffer if clients connected in between calls.
+            let count = unsafe { (self.clients_callback)(buffer.as_mut_ptr(), buffer.len() as u32) }
+                as usize;
+            if count <= buffer.len() {
+                buffer.truncate(count);
+                return buffer.into_iter().map(ClientId).collect();
+            }
+            buffer.resize(count, 0);
+        }
+    }
+
+    fn client_count(&self) -> usize {
+        unsafe { (self.clients_callback)(std::ptr::null_mut(), 0) as usize }
+    }
+
+    fn client_protocol(&self, client: ClientId) -> Option<String> {
+        self.protocols.lock().unwrap().get(&client).cloned()
+    }
+
+    fn close_room(&self, reason: &str) {
+        self.send(&MessageFromProcess::CloseRoom {
+            reason: reason.to_string(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::WasmStateroomContext;
+    use stateroom::{ClientId, StateroomContext};
+    use std::sync::{
+        atomic::{AtomicU32, Ordering},
+        Mutex,
+    };
+
+    static CALLS: AtomicU32 = AtomicU32::new(0);
+
+    unsafe extern "C" fn ignore_message(_: *const u8, _: u32) {}
+
+    /// Reports two clients, then a third one joining after the guest sized its buffer.
+    unsafe extern "C" fn growing_clients(buffer: *mut u32, len: u32) -> u32 {
+        let clients: &[u32] = match CALLS.fetch_add(1, Ordering::SeqCst) {
+            0 => &[1, 2],
+            _ => &[1, 2, 3],
+        };
+        let written = clients.len().min(len as usize);
+        if written > 0 {
+            std::ptr::copy_nonoverlapping(clients.as_ptr(), buffer, written);
+        }
+        clients.len() as u32
+    }
+
+    #[test]
+    fn test_connected_clients_retries_with_larger_buffer() {
+        let context = WasmStateroomContext {
+            callback: ignore_message,
+            clients_callback: growing_clients,
+            protocols: Mutex::default(),
+        };
+
+        assert_eq!(
+            context.connected_clients(),
+            vec![ClientId(1), ClientId(2), ClientId(3)]
+        );
+        // Sized for nothing, then for two clients, then for three.
+        assert_eq!(CALLS.load(Ordering::SeqCst), 3);
+    }
 }
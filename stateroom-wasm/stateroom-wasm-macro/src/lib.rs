@@ -35,6 +35,8 @@ fn stateroom_wasm_impl(item: &proc_macro2::TokenStream) -> proc_macro2::TokenStr
             mod ffi {
                 extern "C" {
                     pub fn stateroom_send(message_ptr: *const u8, message_len: u32);
+
+                    pub fn stateroom_connected_clients(clients_ptr: *mut u32, clients_len: u32) -> u32;
                 }
             }
 
@@ -42,7 +44,7 @@ fn stateroom_wasm_impl(item: &proc_macro2::TokenStream) -> proc_macro2::TokenStr
             static mut SERVER_STATE: Option<stateroom_wasm::WrappedStateroomService<#name>> = None;
 
             #[no_mangle]
-            pub static STATEROOM_API_VERSION: i32 = 1;
+            pub static STATEROOM_API_VERSION: i32 = 2;
 
             #[no_mangle]
             pub static STATEROOM_API_PROTOCOL: i32 = 0;
@@ -53,7 +55,11 @@ fn stateroom_wasm_impl(item: &proc_macro2::TokenStream) -> proc_macro2::TokenStr
                     match SERVER_STATE.as_mut() {
                         Some(s) => s,
                         None => {
-                            let s = stateroom_wasm::WrappedStateroomService::new(#name::default(), ffi::stateroom_send);
+                            let s = stateroom_wasm::WrappedStateroomService::new(
+                                #name::default(),
+                                ffi::stateroom_send,
+                                ffi::stateroom_connected_clients,
+                            );
                             SERVER_STATE.replace(s);
                             SERVER_STATE.as_mut().unwrap()
                         }
@@ -83,11 +89,80 @@ fn stateroom_wasm_impl(item: &proc_macro2::TokenStream) -> proc_macro2::TokenStr
     }
 }
 
+fn stateroom_wasm_component_impl(item: &proc_macro2::TokenStream) -> proc_macro2::TokenStream {
+    let name =
+        get_name(item).expect("Can only use #[stateroom_wasm] on a struct, enum, or type alias.");
+
+    quote! {
+        #item
+
+        mod _stateroom_wasm_macro_autogenerated {
+            use super::#name;
+            use stateroom_wasm::component::{types, ComponentStateroomService, Guest};
+
+            // Instance-global stateroom service.
+            static mut SERVER_STATE: Option<ComponentStateroomService<#name>> = None;
+
+            fn state() -> &'static mut ComponentStateroomService<#name> {
+                unsafe {
+                    (*core::ptr::addr_of_mut!(SERVER_STATE))
+                        .get_or_insert_with(|| ComponentStateroomService::new(#name::default()))
+                }
+            }
+
+            struct Component;
+
+            impl Guest for Component {
+                fn init(_room_id: String) {
+                    state().init();
+                }
+
+                fn connect(client: types::ClientId) {
+                    state().connect(client);
+                }
+
+                fn disconnect(client: types::ClientId) {
+                    state().disconnect(client);
+                }
+
+                fn message(sender: types::ClientId, message: types::MessagePayload) {
+                    state().message(sender, message);
+                }
+
+                fn timer() {
+                    state().timer();
+                }
+
+                fn rate_limited(client: types::ClientId) {
+                    state().rate_limited(client);
+                }
+
+                fn shutdown() {
+                    state().shutdown();
+                }
+            }
+
+            stateroom_wasm::component::export_component!(Component);
+        }
+    }
+}
+
 /// Exposes a `stateroom_wasm::StateroomService`-implementing trait as a WebAssembly module.
+///
+/// With `#[stateroom_wasm(component)]`, the service is instead exposed as a WebAssembly
+/// component that targets the `stateroom:service` WIT world. This requires the
+/// `component` feature of `stateroom-wasm`.
 #[proc_macro_attribute]
-pub fn stateroom_wasm(_attr: TokenStream, item: TokenStream) -> TokenStream {
-    #[allow(clippy::needless_borrow)]
-    stateroom_wasm_impl(&item.into()).into()
+pub fn stateroom_wasm(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let attr = attr.to_string();
+    match attr.as_str() {
+        "" => stateroom_wasm_impl(&item.into()).into(),
+        "component" => stateroom_wasm_component_impl(&item.into()).into(),
+        _ => panic!(
+            "Unexpected argument to #[stateroom_wasm]: {:?}. Expected nothing or `component`.",
+            attr
+        ),
+    }
 }
 
 #[cfg(test)]
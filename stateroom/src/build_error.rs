@@ -0,0 +1,18 @@
+/// Describes why a [crate::StateroomServiceFactory] could not build a service.
+///
+/// Servers use this to decide how to report the failure to a connecting client;
+/// for example, `stateroom-server` maps each kind to an HTTP status code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuildErrorKind {
+    /// The requested room does not exist.
+    NotFound,
+
+    /// The client is not allowed to access the requested room.
+    Forbidden,
+
+    /// The service could not be built right now, but may be available later.
+    Unavailable,
+
+    /// The service could not be built due to an internal error.
+    Internal,
+}
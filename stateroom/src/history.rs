@@ -0,0 +1,162 @@
+use crate::{ClientId, MessagePayload, StateroomContext};
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+/// How many messages a [HistoryBuffer] keeps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HistoryLimit {
+    /// The number of most recent messages to keep. Zero disables the history.
+    pub max_messages: usize,
+
+    /// If set, messages older than this are discarded, even if there is room for them.
+    pub max_age: Option<Duration>,
+}
+
+impl HistoryLimit {
+    /// Keeps the last `max_messages` messages, regardless of their age.
+    pub fn messages(max_messages: usize) -> Self {
+        HistoryLimit {
+            max_messages,
+            max_age: None,
+        }
+    }
+
+    #[must_use]
+    pub fn with_max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+}
+
+/// Keeps the most recent messages sent in a room, so that they can be replayed to clients
+/// that join later.
+///
+/// ```
+/// use stateroom::*;
+///
+/// struct ChatServer {
+///     history: HistoryBuffer,
+/// }
+///
+/// impl StateroomService for ChatServer {
+///     fn connect(&mut self, client: ClientId, ctx: &impl StateroomContext) {
+///         self.history.replay(client, ctx);
+///     }
+///
+///     fn message(&mut self, _: ClientId, message: MessagePayload, ctx: &impl StateroomContext) {
+///         self.history.push(message.clone());
+///         ctx.send_message(MessageRecipient::Broadcast, message);
+///     }
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct HistoryBuffer<T = MessagePayload> {
+    limit: HistoryLimit,
+    messages: VecDeque<(Instant, T)>,
+}
+
+impl<T> HistoryBuffer<T> {
+    pub fn new(limit: HistoryLimit) -> Self {
+        HistoryBuffer {
+            limit,
+            messages: VecDeque::new(),
+        }
+    }
+
+    pub fn limit(&self) -> HistoryLimit {
+        self.limit
+    }
+
+    /// Adds a message, discarding the oldest one if the buffer is full.
+    pub fn push(&mut self, message: T) {
+        self.push_at(Instant::now(), message);
+    }
+
+    /// Returns the messages that are still within the limit, oldest first.
+    pub fn messages(&mut self) -> impl Iterator<Item = &T> + '_ {
+        self.expire(Instant::now());
+        self.messages.iter().map(|(_, message)| message)
+    }
+
+    /// Discards every message.
+    pub fn clear(&mut self) {
+        self.messages.clear();
+    }
+
+    fn push_at(&mut self, now: Instant, message: T) {
+        if self.limit.max_messages == 0 {
+            return;
+        }
+
+        self.expire(now);
+        if self.messages.len() == self.limit.max_messages {
+            self.messages.pop_front();
+        }
+        self.messages.push_back((now, message));
+    }
+
+    fn expire(&mut self, now: Instant) {
+        let Some(max_age) = self.limit.max_age else {
+            return;
+        };
+
+        while let Some((time, _)) = self.messages.front() {
+            if now.saturating_duration_since(*time) <= max_age {
+                break;
+            }
+            self.messages.pop_front();
+        }
+    }
+}
+
+impl<T: Clone + Into<MessagePayload>> HistoryBuffer<T> {
+    /// Sends the messages that are still within the limit to a client, oldest first.
+    pub fn replay(&mut self, client: ClientId, context: &impl StateroomContext) {
+        for message in self.messages() {
+            context.send_message(client, message.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{HistoryBuffer, HistoryLimit};
+    use std::time::{Duration, Instant};
+
+    fn contents(history: &mut HistoryBuffer<u32>) -> Vec<u32> {
+        history.messages().copied().collect()
+    }
+
+    #[test]
+    fn test_max_messages() {
+        let mut history = HistoryBuffer::new(HistoryLimit::messages(2));
+        history.push(1);
+        history.push(2);
+        history.push(3);
+        assert_eq!(contents(&mut history), vec![2, 3]);
+
+        let mut disabled = HistoryBuffer::new(HistoryLimit::messages(0));
+        disabled.push(1);
+        assert!(contents(&mut disabled).is_empty());
+    }
+
+    #[test]
+    fn test_max_age() {
+        let start = Instant::now();
+        let secs = |secs| start + Duration::from_secs(secs);
+        let limit = HistoryLimit::messages(10).with_max_age(Duration::from_secs(5));
+
+        let mut history = HistoryBuffer::new(limit);
+        history.push_at(secs(0), 1);
+        history.push_at(secs(3), 2);
+        history.push_at(secs(6), 3);
+        assert_eq!(history.messages.len(), 2);
+
+        history.expire(secs(9));
+        assert_eq!(history.messages.len(), 1);
+        history.expire(secs(12));
+        assert!(history.messages.is_empty());
+    }
+}
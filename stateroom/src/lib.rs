@@ -65,20 +65,34 @@
 
 use std::{convert::Infallible, sync::Arc};
 
+pub use build_error::BuildErrorKind;
 pub use client_id::ClientId;
+pub use history::{HistoryBuffer, HistoryLimit};
 pub use message_recipient::MessageRecipient;
-pub use messages::{MessageFromProcess, MessagePayload, MessageToProcess};
+pub use messages::{
+    BinaryPayload, MessageFromProcess, MessagePayload, MessageToProcess, TextPayload,
+};
+pub use output_line::{OutputLine, OutputSource};
+pub use service_info::ServiceInfo;
+#[cfg(feature = "bytes")]
+pub use {bytes::Bytes, bytestring::ByteString};
 
+mod build_error;
 mod client_id;
+mod history;
 mod message_recipient;
 mod messages;
+mod output_line;
+mod service_info;
 
 /// Provides an interface for a [StateroomService] instance to send messages back to its host environment.
+#[allow(unused_variables)]
 pub trait StateroomContext: Send + Sync + 'static {
     /// Sends a message to a currently connected user, or broadcast a message to all users.
     ///
-    /// Recipient can be a `u32` representing an individual user to send a message to, or
-    /// `MessageRecipient::Broadcast` to broadcast a message to all connected users.
+    /// Recipient can be a [ClientId] representing an individual user to send a message to,
+    /// `MessageRecipient::Broadcast` to broadcast a message to all connected users, or any other
+    /// [MessageRecipient] such as a list of clients or a named group.
     /// The message is a string which is sent verbatim to the user(s) indicated.
     fn send_message(
         &self,
@@ -94,6 +108,45 @@ pub trait StateroomContext: Send + Sync + 'static {
     /// stored in your service. For example, you could implement multiple concurrent timers using a
     /// priority queue and ensuring that the environment timer always reflects the head of the queue.
     fn set_timer(&self, ms_delay: u32);
+
+    /// Adds a client to the named group, creating the group if it does not exist.
+    ///
+    /// Messages sent to [MessageRecipient::Group] are delivered to every member of the group.
+    /// Clients are removed from all of their groups when they disconnect, and clients
+    /// that are not connected can't join a group. Hosts without groups ignore this.
+    fn join_group(&self, client: ClientId, group: &str) {}
+
+    /// Removes a client from the named group. Groups with no remaining members are discarded.
+    fn leave_group(&self, client: ClientId, group: &str) {}
+
+    /// Returns the IDs of all clients currently connected to the service, in ascending order.
+    ///
+    /// This reflects the host's view of the room, so services do not need to mirror
+    /// `connect` and `disconnect` calls to know who is present. Hosts that don't track
+    /// clients return an empty list.
+    fn connected_clients(&self) -> Vec<ClientId> {
+        Vec::new()
+    }
+
+    /// Returns the number of clients currently connected to the service.
+    fn client_count(&self) -> usize {
+        self.connected_clients().len()
+    }
+
+    /// Returns the WebSocket subprotocol negotiated with a client, if any.
+    ///
+    /// The protocol is known by the time [StateroomService::connect] is called for the
+    /// client, so services can use it to decide how to encode messages for each client.
+    fn client_protocol(&self, client: ClientId) -> Option<String> {
+        None
+    }
+
+    /// Asks the host to close the room, for example when a match is over.
+    ///
+    /// The host calls [StateroomService::shutdown] once the current call into the service
+    /// returns, then disconnects every client with `reason` in the WebSocket close frame,
+    /// and discards the room. Hosts that can't close rooms ignore this.
+    fn close_room(&self, reason: &str) {}
 }
 
 /// A simplified interface for creating a [StateroomService] that can be exposed as a WebAssembly module.
@@ -104,7 +157,8 @@ pub trait StateroomService: Send + Sync + 'static {
     /// Called when the service is created, before any client has had a chance to connect.
     fn init(&mut self, context: &impl StateroomContext) {}
 
-    /// Called each time a client connects to the service.
+    /// Called each time a client connects to the service. The subprotocol the client
+    /// connected with is available from [StateroomContext::client_protocol].
     fn connect(&mut self, client: ClientId, context: &impl StateroomContext) {}
 
     /// Called each time a client disconnects from the service, unless that disconnection
@@ -123,8 +177,18 @@ pub trait StateroomService: Send + Sync + 'static {
     /// Called when [StateroomContext::set_timer] has been called on this service's context,
     /// after the provided duration.
     fn timer(&mut self, context: &impl StateroomContext) {}
+
+    /// Called when a client's message was dropped because the client exceeded a rate limit,
+    /// if the host is configured to notify the service.
+    fn rate_limited(&mut self, client: ClientId, context: &impl StateroomContext) {}
+
+    /// Called when the room is closing because the service called
+    /// [StateroomContext::close_room]. Clients are still connected, so messages sent
+    /// here are delivered before the connections are closed.
+    fn shutdown(&mut self, context: &impl StateroomContext) {}
 }
 
+#[allow(unused_variables)]
 pub trait StateroomServiceFactory: Send + Sync + 'static {
     /// The type of [StateroomService] that the object implementing this trait builds.
     type Service: StateroomService;
@@ -136,6 +200,55 @@ pub trait StateroomServiceFactory: Send + Sync + 'static {
         room_id: &str,
         context: Arc<impl StateroomContext>,
     ) -> Result<Self::Service, Self::Error>;
+
+    /// Build a [StateroomService] for a room that was created with an initial configuration
+    /// payload, such as the body of a room creation request.
+    ///
+    /// By default the configuration is ignored and [StateroomServiceFactory::build] is called.
+    fn build_with_config(
+        &self,
+        room_id: &str,
+        config: &[u8],
+        context: Arc<impl StateroomContext>,
+    ) -> Result<Self::Service, Self::Error> {
+        self.build(room_id, context)
+    }
+
+    /// Classifies an error returned by [StateroomServiceFactory::build], so that the
+    /// host can report it appropriately. Defaults to [BuildErrorKind::Internal].
+    fn error_kind(&self, error: &Self::Error) -> BuildErrorKind {
+        BuildErrorKind::Internal
+    }
+
+    /// Chooses the WebSocket subprotocol for a client connecting to the given room.
+    ///
+    /// `protocols` holds the subprotocols that both the client requested and the host
+    /// supports, in the host's order of preference. Returning `None` (or a protocol not
+    /// in the list) accepts the connection without a subprotocol. Defaults to the first.
+    fn select_protocol(&self, room_id: &str, protocols: &[String]) -> Option<String> {
+        protocols.first().cloned()
+    }
+
+    /// Describes the service built by this factory, for status reporting.
+    fn service_info(&self) -> ServiceInfo {
+        ServiceInfo::default()
+    }
+
+    /// Returns the most recent lines of output written by the service in the given room,
+    /// oldest first, for diagnostics. Returns `None` if the room is unknown or the factory
+    /// doesn't keep output, which is the default.
+    fn recent_output(&self, room_id: &str) -> Option<Vec<OutputLine>> {
+        None
+    }
+
+    /// How many of a room's broadcast messages the host keeps and replays to clients that
+    /// join the room later, before they receive any other messages.
+    ///
+    /// `None`, the default, uses the host's setting. Return a limit of zero messages to
+    /// disable the history for a room.
+    fn history_limit(&self, room_id: &str) -> Option<HistoryLimit> {
+        None
+    }
 }
 
 #[derive(Default)]
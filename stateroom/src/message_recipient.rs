@@ -5,8 +5,9 @@ use serde::{Deserialize, Serialize};
 /// Represents the recipient(s) of a message.
 ///
 /// Messages may either be sent to a particular client by numeric id
-/// (`MessageRecipient::Client(3)`), or be broadcast to all connected clients
-/// (`MessageRecipient::Broadcast`).]
+/// (`MessageRecipient::Client(3)`), to a set of clients, to the members of a
+/// named group, or be broadcast to all connected clients
+/// (`MessageRecipient::Broadcast`).
 #[derive(Debug, Clone, PartialEq)]
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 
@@ -14,15 +15,39 @@ pub enum MessageRecipient {
     Broadcast,
     Client(ClientId),
     EveryoneExcept(ClientId),
+
+    /// Every client in the list.
+    Clients(Vec<ClientId>),
+
+    /// Every connected client that is not in the list.
+    EveryoneExceptMany(Vec<ClientId>),
+
+    /// Every client that has joined the named group with [crate::StateroomContext::join_group].
+    Group(String),
 }
 
 impl MessageRecipient {
+    /// Encodes the recipient as an `i32`.
+    ///
+    /// # Panics
+    ///
+    /// Only `Broadcast`, `Client` and `EveryoneExcept` have an `i32` encoding. Use
+    /// [MessageRecipient::try_encode_i32] for recipients that may not.
     #[must_use]
     pub fn encode_i32(&self) -> i32 {
+        self.try_encode_i32()
+            .expect("recipient has no i32 encoding")
+    }
+
+    /// Encodes the recipient as an `i32`, or returns `None` for recipients other than
+    /// `Broadcast`, `Client` and `EveryoneExcept`.
+    #[must_use]
+    pub fn try_encode_i32(&self) -> Option<i32> {
         match self {
-            Self::Broadcast => 0,
-            Self::Client(c) => c.0 as i32,
-            Self::EveryoneExcept(c) => -(c.0 as i32),
+            Self::Broadcast => Some(0),
+            Self::Client(c) => Some(c.0 as i32),
+            Self::EveryoneExcept(c) => Some(-(c.0 as i32)),
+            _ => None,
         }
     }
 
@@ -42,6 +67,12 @@ impl From<ClientId> for MessageRecipient {
     }
 }
 
+impl From<Vec<ClientId>> for MessageRecipient {
+    fn from(c: Vec<ClientId>) -> Self {
+        MessageRecipient::Clients(c)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::{ClientId, MessageRecipient};
@@ -62,6 +93,10 @@ mod tests {
         assert_eq!(443, MessageRecipient::Client(443.into()).encode_i32());
 
         assert_eq!(-4, MessageRecipient::EveryoneExcept(4.into()).encode_i32());
+        assert_eq!(
+            Some(-4),
+            MessageRecipient::EveryoneExcept(4.into()).try_encode_i32()
+        );
         assert_eq!(
             MessageRecipient::EveryoneExcept(119.into()),
             MessageRecipient::decode_i32(-119)
@@ -72,4 +107,31 @@ mod tests {
             MessageRecipient::decode_i32(-1)
         );
     }
+
+    #[test]
+    fn test_encode_multiple_recipients() {
+        assert_eq!(
+            MessageRecipient::Clients(vec![1.into(), 2.into()]),
+            vec![ClientId::from(1u32), ClientId::from(2u32)].into()
+        );
+
+        assert_eq!(
+            None,
+            MessageRecipient::Clients(vec![1.into(), 2.into()]).try_encode_i32()
+        );
+        assert_eq!(
+            None,
+            MessageRecipient::EveryoneExceptMany(vec![3.into()]).try_encode_i32()
+        );
+        assert_eq!(
+            None,
+            MessageRecipient::Group("red-team".to_string()).try_encode_i32()
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "recipient has no i32 encoding")]
+    fn test_encode_group_panics() {
+        let _ = MessageRecipient::Group("red-team".to_string()).encode_i32();
+    }
 }
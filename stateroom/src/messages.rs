@@ -1,13 +1,31 @@
 #[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
 
-use crate::{ClientId, MessageRecipient};
+use crate::{ClientId, MessageRecipient, StateroomContext};
 
+/// The contents of a binary message. With the `bytes` feature, this is a reference-counted
+/// [bytes::Bytes], which hosts can pass between layers without copying.
+#[cfg(not(feature = "bytes"))]
+pub type BinaryPayload = Vec<u8>;
+
+#[cfg(feature = "bytes")]
+pub type BinaryPayload = bytes::Bytes;
+
+/// The contents of a text message. With the `bytes` feature, this is a
+/// [bytestring::ByteString], which is backed by [bytes::Bytes].
+#[cfg(not(feature = "bytes"))]
+pub type TextPayload = String;
+
+#[cfg(feature = "bytes")]
+pub type TextPayload = bytestring::ByteString;
+
+/// A WebSocket message. Both representations have the same serialized form, so hosts and
+/// guests built with and without the `bytes` feature can exchange messages.
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum MessagePayload {
-    Bytes(Vec<u8>),
-    Text(String),
+    Bytes(BinaryPayload),
+    Text(TextPayload),
 }
 
 impl MessagePayload {
@@ -26,27 +44,46 @@ impl MessagePayload {
     }
 }
 
+// Without the `bytes` feature, the `into` calls below are no-ops.
+
 impl Into<MessagePayload> for String {
+    #[allow(clippy::useless_conversion)]
     fn into(self) -> MessagePayload {
-        MessagePayload::Text(self)
+        MessagePayload::Text(self.into())
     }
 }
 
 impl Into<MessagePayload> for &str {
     fn into(self) -> MessagePayload {
-        MessagePayload::Text(self.to_string())
+        MessagePayload::Text(self.into())
     }
 }
 
 impl Into<MessagePayload> for Vec<u8> {
+    #[allow(clippy::useless_conversion)]
     fn into(self) -> MessagePayload {
-        MessagePayload::Bytes(self)
+        MessagePayload::Bytes(self.into())
     }
 }
 
 impl Into<MessagePayload> for &[u8] {
+    #[allow(clippy::useless_conversion)]
     fn into(self) -> MessagePayload {
-        MessagePayload::Bytes(self.to_vec())
+        MessagePayload::Bytes(self.to_vec().into())
+    }
+}
+
+#[cfg(feature = "bytes")]
+impl From<bytes::Bytes> for MessagePayload {
+    fn from(bytes: bytes::Bytes) -> Self {
+        MessagePayload::Bytes(bytes)
+    }
+}
+
+#[cfg(feature = "bytes")]
+impl From<bytestring::ByteString> for MessagePayload {
+    fn from(text: bytestring::ByteString) -> Self {
+        MessagePayload::Text(text)
     }
 }
 
@@ -56,6 +93,7 @@ pub enum MessageToProcess {
     Init,
     Connect {
         client: ClientId,
+        protocol: Option<String>,
     },
     Disconnect {
         client: ClientId,
@@ -65,6 +103,10 @@ pub enum MessageToProcess {
         message: MessagePayload,
     },
     Timer,
+    RateLimited {
+        client: ClientId,
+    },
+    Shutdown,
 }
 
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
@@ -76,4 +118,99 @@ pub enum MessageFromProcess {
     SetTimer {
         ms_delay: u32,
     },
+    JoinGroup {
+        client: ClientId,
+        group: String,
+    },
+    LeaveGroup {
+        client: ClientId,
+        group: String,
+    },
+    CloseRoom {
+        reason: String,
+    },
+}
+
+impl MessageFromProcess {
+    /// Carries out a message from a service running outside of the host, by calling the
+    /// corresponding method of the room's context.
+    pub fn dispatch(self, context: &impl StateroomContext) {
+        match self {
+            MessageFromProcess::Message { recipient, message } => {
+                context.send_message(recipient, message);
+            }
+            MessageFromProcess::SetTimer { ms_delay } => {
+                context.set_timer(ms_delay);
+            }
+            MessageFromProcess::JoinGroup { client, group } => {
+                context.join_group(client, &group);
+            }
+            MessageFromProcess::LeaveGroup { client, group } => {
+                context.leave_group(client, &group);
+            }
+            MessageFromProcess::CloseRoom { reason } => {
+                context.close_room(&reason);
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "bytes", feature = "serde"))]
+mod tests {
+    use super::MessagePayload;
+    use serde::{Deserialize, Serialize};
+
+    /// [MessagePayload] as it is without the `bytes` feature.
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum PlainPayload {
+        Bytes(Vec<u8>),
+        Text(String),
+    }
+
+    fn payloads() -> Vec<(MessagePayload, PlainPayload)> {
+        vec![
+            ("hello".into(), PlainPayload::Text("hello".to_string())),
+            (vec![0, 1, 255].into(), PlainPayload::Bytes(vec![0, 1, 255])),
+            (Vec::new().into(), PlainPayload::Bytes(Vec::new())),
+        ]
+    }
+
+    fn assert_same(payload: &MessagePayload, plain: &PlainPayload) {
+        match plain {
+            PlainPayload::Text(text) => assert_eq!(payload.text(), Some(text.as_str())),
+            PlainPayload::Bytes(bytes) => assert_eq!(payload.bytes(), Some(bytes.as_slice())),
+        }
+    }
+
+    #[test]
+    fn test_bincode_matches_plain_payload() {
+        for (payload, plain) in payloads() {
+            let encoded = bincode::serialize(&payload).unwrap();
+            assert_eq!(encoded, bincode::serialize(&plain).unwrap());
+            assert_eq!(
+                bincode::deserialize::<PlainPayload>(&encoded).unwrap(),
+                plain
+            );
+
+            let decoded: MessagePayload =
+                bincode::deserialize(&bincode::serialize(&plain).unwrap()).unwrap();
+            assert_same(&decoded, &plain);
+        }
+    }
+
+    #[test]
+    fn test_json_matches_plain_payload() {
+        for (payload, plain) in payloads() {
+            let encoded = serde_json::to_string(&payload).unwrap();
+            assert_eq!(encoded, serde_json::to_string(&plain).unwrap());
+            assert_eq!(
+                serde_json::from_str::<PlainPayload>(&encoded).unwrap(),
+                plain
+            );
+
+            let decoded: MessagePayload =
+                serde_json::from_str(&serde_json::to_string(&plain).unwrap()).unwrap();
+            assert_same(&decoded, &plain);
+        }
+    }
 }
@@ -0,0 +1,21 @@
+use std::time::SystemTime;
+
+/// The stream that a service wrote a line of output to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputSource {
+    Stdout,
+    Stderr,
+}
+
+/// A line that a room's service wrote to its standard output or error, as returned by
+/// [crate::StateroomServiceFactory::recent_output].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutputLine {
+    pub source: OutputSource,
+
+    /// The line, without its line ending. Invalid UTF-8 is replaced.
+    pub text: String,
+
+    /// When the line was completed.
+    pub time: SystemTime,
+}
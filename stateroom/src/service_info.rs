@@ -0,0 +1,11 @@
+/// Describes the service that a [crate::StateroomServiceFactory] builds, for status reporting.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ServiceInfo {
+    /// A hash identifying the code of the service, such as the SHA-256 digest of a
+    /// WebAssembly module.
+    pub module_hash: Option<String>,
+
+    /// The newest Stateroom API version that the host supports for the service. Hosts
+    /// may also accept services built against older versions.
+    pub api_version: Option<i32>,
+}